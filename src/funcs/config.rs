@@ -1,9 +1,15 @@
-/// Imports Section <> Value names from a INI file
+//! Imports Section <> Value names from a INI file
 
+use chrono::NaiveDateTime;
 use ini::Ini;
+use regex::Regex;
+use rust_decimal::{Decimal, RoundingStrategy};
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::{error::Error, fmt::Debug};
+use std::fmt::Debug;
+
+use crate::funcs::trade::parse_datetime_string;
+use crate::funcs::txn_type::{AmountSign, IncomeKind, TxnPattern, TxnRule, TxnType};
 
 
 #[derive(thiserror::Error, Debug)]
@@ -12,16 +18,35 @@ pub enum ConfigParseError {
     IniLoadError(#[from] ini::Error),
     #[error("Could not derive intended transaction filepath from config input")]
     FilepathError,
+    #[error("Config is missing required section [{0}]")]
+    MissingSection(String),
+    #[error("Config is missing required key '{key}' in section [{section}]")]
+    MissingKey {
+        section: String,
+        key: String,
+    },
+    #[error("Could not parse value '{value}' for '{key}' in section [{section}]")]
+    InvalidValue {
+        section: String,
+        key: String,
+        value: String,
+    },
 }
 
 
-/// Enum of three different types of analysis types
+/// Enum of the different types of analysis types
 #[derive(Debug, Default, Clone)]
 pub enum AccountingType {
     #[default]
     LIFO,
     FIFO,
     HIFO,
+    LOFO,
+    HTFO, // Highest tax first out: short-term losses, long-term losses, long-term gains, then short-term gains
+    MinTax, // Lowest estimated tax per unit first, using `TaxRates`
+    ACB, // Adjusted cost base: a single pooled average cost per asset
+    UkPooling, // HMRC same-day, 30-day and Section 104 pool matching
+    SpecificId, // Lots chosen per sale from a lot-assignment file, see `SpecificIdConfig`
 }
 
 impl AccountingType {
@@ -31,71 +56,549 @@ impl AccountingType {
             "LIFO" => Some(Self::LIFO),
             "FIFO" => Some(Self::FIFO),
             "HIFO" => Some(Self::HIFO),
+            "LOFO" => Some(Self::LOFO),
+            "HTFO" => Some(Self::HTFO),
+            "MIN_TAX" | "MINTAX" => Some(Self::MinTax),
+            "ACB" | "AVERAGE" => Some(Self::ACB),
+            "UK" | "UK_POOLING" | "UKPOOLING" => Some(Self::UkPooling),
+            "SPECIFIC_ID" | "SPECIFICID" => Some(Self::SpecificId),
+            _ => None,
+        }
+    }
+}
+
+/// Rounding applied to monetary values at report time. Matching itself is always exact
+#[derive(Debug, Clone)]
+pub struct Rounding {
+    pub decimal_places: u32,
+    pub strategy: RoundingStrategy,
+}
+
+impl Default for Rounding {
+    fn default() -> Self {
+        Self {
+            decimal_places: 2,
+            strategy: RoundingStrategy::MidpointAwayFromZero,
+        }
+    }
+}
+
+impl Rounding {
+    /// Rounds to the configured places, padding with trailing zeros so reports have a fixed scale
+    pub fn apply(&self, value: Decimal) -> Decimal {
+        let mut rounded = value.round_dp_with_strategy(self.decimal_places, self.strategy);
+        rounded.rescale(self.decimal_places);
+        rounded
+    }
+
+    /// Matches a rounding strategy string to a `RoundingStrategy`
+    fn match_strategy(strategy: &str) -> Option<RoundingStrategy> {
+        match strategy.to_lowercase().as_str() {
+            "half_up" => Some(RoundingStrategy::MidpointAwayFromZero),
+            "half_down" => Some(RoundingStrategy::MidpointTowardZero),
+            "half_even" => Some(RoundingStrategy::MidpointNearestEven),
+            "up" => Some(RoundingStrategy::AwayFromZero),
+            "down" | "truncate" => Some(RoundingStrategy::ToZero),
+            _ => None,
+        }
+    }
+}
+
+/// Minimum holding before a disposal counts as long-term. US default: more than 12 months
+#[derive(Debug, Clone)]
+pub struct HoldingPeriodRule {
+    pub long_term_months: u32,
+}
+
+impl Default for HoldingPeriodRule {
+    fn default() -> Self {
+        Self { long_term_months: 12 }
+    }
+}
+
+/// Marginal tax rate assumptions used by the `MinTax` accounting type
+#[derive(Debug, Clone)]
+pub struct TaxRates {
+    pub short_term: Decimal,
+    pub long_term: Decimal,
+}
+
+impl Default for TaxRates {
+    fn default() -> Self {
+        Self {
+            short_term: Decimal::new(37, 2),
+            long_term: Decimal::new(20, 2),
+        }
+    }
+}
+
+/// What to do with a sale that exceeds the holdings available to match it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum ShortfallPolicy {
+    #[default]
+    Fail,
+    ZeroBasis, // Match the unmatched amount against a zero-cost lot acquired at the sale
+    OpeningBalance, // Match the unmatched amount against the asset's `[opening_balance]` lot
+}
+
+impl ShortfallPolicy {
+    fn match_policy(policy: &str) -> Option<Self> {
+        match policy.to_lowercase().as_str() {
+            "fail" => Some(Self::Fail),
+            "zero_basis" => Some(Self::ZeroBasis),
+            "opening_balance" => Some(Self::OpeningBalance),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with csv rows that fail to parse
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum ImportErrorPolicy {
+    #[default]
+    Fail, // Stop at the first bad row
+    Collect, // Parse every row, then fail with all the bad rows
+    Skip, // Parse every row, report the bad ones and continue with the rest
+}
+
+impl ImportErrorPolicy {
+    fn match_policy(policy: &str) -> Option<Self> {
+        match policy.to_lowercase().as_str() {
+            "fail" => Some(Self::Fail),
+            "collect" => Some(Self::Collect),
+            "skip" => Some(Self::Skip),
+            _ => None,
+        }
+    }
+}
+
+/// A lot held before the first imported trade
+#[derive(Debug, Clone)]
+pub struct OpeningBalance {
+    pub amount: Decimal,
+    pub cost_basis: Decimal,
+    pub acquired: NaiveDateTime,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct InventoryConfig {
+    pub policy: ShortfallPolicy,
+    pub opening_balances: HashMap<String, OpeningBalance>,
+}
+
+/// Lot-assignment file for specific identification, and the method used for unassigned quantity
+#[derive(Debug, Clone)]
+pub struct SpecificIdConfig {
+    pub filepath: PathBuf,
+    pub default_method: AccountingType,
+}
+
+impl Default for SpecificIdConfig {
+    fn default() -> Self {
+        Self {
+            filepath: PathBuf::new(),
+            default_method: AccountingType::FIFO,
+        }
+    }
+}
+
+/// Local price history used to value crypto-to-crypto trades, see `price_source`
+#[derive(Debug, Clone)]
+pub struct PriceSourceConfig {
+    pub filepath: PathBuf, // csv, or parquet with the `parquet` feature. Empty when no price history is used
+    pub currency: String, // Currency the prices are quoted in
+}
+
+impl Default for PriceSourceConfig {
+    fn default() -> Self {
+        Self {
+            filepath: PathBuf::new(),
+            currency: "USD".to_string(),
+        }
+    }
+}
+
+/// Layout of the trades csv. Generic uses `[csv_columns]`, the others read an exchange's own export
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum FileFormat {
+    #[default]
+    Generic,
+    Coinbase, // Transaction history report
+    Kraken, // Ledgers or trades export
+    Binance, // Spot trade history
+    Gemini, // Transaction history
+}
+
+impl FileFormat {
+    fn match_format(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "generic" => Some(Self::Generic),
+            "coinbase" => Some(Self::Coinbase),
+            "kraken" => Some(Self::Kraken),
+            "binance" => Some(Self::Binance),
+            "gemini" => Some(Self::Gemini),
+            _ => None,
+        }
+    }
+}
+
+/// Decides the order of trades that share a timestamp
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TieBreaker {
+    SourceOrder, // Row order in the csv
+    #[default]
+    BuysFirst, // Acquisitions before disposals, then row order
+    TxnId, // By user txn id, then row order. Trades without one go last
+}
+
+impl TieBreaker {
+    fn match_tie_breaker(tie_breaker: &str) -> Option<Self> {
+        match tie_breaker.to_lowercase().as_str() {
+            "source_order" => Some(Self::SourceOrder),
+            "buys_first" => Some(Self::BuysFirst),
+            "txn_id" => Some(Self::TxnId),
+            _ => None,
+        }
+    }
+}
+
+/// Which side of a swap its fair value is taken from
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SwapValuation {
+    #[default]
+    Auto, // The side priced in the price history, else the disposal
+    Disposal, // The assets given up
+    Acquisition, // The assets received
+}
+
+impl SwapValuation {
+    fn match_valuation(valuation: &str) -> Option<Self> {
+        match valuation.to_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "disposal" => Some(Self::Disposal),
+            "acquisition" => Some(Self::Acquisition),
             _ => None,
         }
     }
 }
 
-#[derive(Debug, Default)]
+/// Rows sharing a txn id with both a sale and a buy form one swap, see `swaps`
+#[derive(Debug, Clone)]
+pub struct SwapConfig {
+    pub tolerance: Decimal, // Largest difference between the sides' stated values, as a fraction of the larger
+    pub fair_value: SwapValuation,
+}
+
+impl Default for SwapConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: Decimal::new(2, 2),
+            fair_value: SwapValuation::default(),
+        }
+    }
+}
+
+/// Deposit and withdrawal txn types, and how closely a deposit must follow a withdrawal to be paired with it
+#[derive(Debug, Clone)]
+pub struct TransferConfig {
+    pub deposit_txn_types: Vec<String>,
+    pub withdrawal_txn_types: Vec<String>,
+    pub amount_tolerance: Decimal, // Largest network fee, as a fraction of the amount withdrawn
+    pub time_window_hours: i64,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            deposit_txn_types: vec![],
+            withdrawal_txn_types: vec![],
+            amount_tolerance: Decimal::new(1, 2),
+            time_window_hours: 24,
+        }
+    }
+}
+
+/// Cut-over from universal to per-venue tracking. Trades before `cutover` are matched without venues,
+/// and the lots in `filepath` (from `safe_harbor::allocate_lots`) start the per-venue processing after it
+#[derive(Debug, Clone)]
+pub struct SafeHarborConfig {
+    pub filepath: PathBuf,
+    pub cutover: NaiveDateTime,
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub accounting_type: AccountingType,
+    pub rounding: Rounding,
+    pub holding_period: HoldingPeriodRule,
+    pub specific_id: SpecificIdConfig,
+    pub tax_rates: TaxRates,
+    pub inventory: InventoryConfig,
+    pub import_errors: ImportErrorPolicy,
+    pub price_source: PriceSourceConfig,
+    pub reporting_currency: String,
+    pub fx_rates: PathBuf, // FX rate table in the price file format. Empty when trades are all in the reporting currency
+    pub opening_lots: PathBuf, // Lots carried in from a prior system, in the starting-lots format. Empty when there are none
     pub filepath: PathBuf,
+    pub file_format: FileFormat,
     pub csv_columns: HashMap<String, String>,
     pub buy_txn_types: Vec<String>,
     pub sell_txn_types: Vec<String>,
-    // pub venues: Option<Vec<String>>, // Optional Field
+    pub income_txn_types: Vec<(IncomeKind, Vec<String>)>, // Checked after buys and sells, in config order
+    pub txn_rules: Vec<TxnRule>, // Tried before the txn type lists, by precedence
+    pub transfers: TransferConfig,
+    pub swaps: SwapConfig,
+    pub tie_breaker: TieBreaker, // Order of trades with the same timestamp
+    pub venues: Option<Vec<String>>, // Set when lots are tracked per venue. Empty when any venue is accepted
+    pub safe_harbor: Option<SafeHarborConfig>,
+    pub open_lots_as_of: Option<NaiveDateTime>, // Date the open-lots report classifies holding periods at. Defaults to the last trade
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            accounting_type: AccountingType::default(),
+            rounding: Rounding::default(),
+            holding_period: HoldingPeriodRule::default(),
+            specific_id: SpecificIdConfig::default(),
+            tax_rates: TaxRates::default(),
+            inventory: InventoryConfig::default(),
+            import_errors: ImportErrorPolicy::default(),
+            price_source: PriceSourceConfig::default(),
+            reporting_currency: "USD".to_string(),
+            fx_rates: PathBuf::new(),
+            opening_lots: PathBuf::new(),
+            filepath: PathBuf::new(),
+            file_format: FileFormat::default(),
+            csv_columns: HashMap::new(),
+            buy_txn_types: vec![],
+            sell_txn_types: vec![],
+            income_txn_types: vec![],
+            txn_rules: vec![],
+            transfers: TransferConfig::default(),
+            swaps: SwapConfig::default(),
+            tie_breaker: TieBreaker::default(),
+            venues: None,
+            safe_harbor: None,
+            open_lots_as_of: None,
+        }
+    }
+}
+
+/// Fields that `[opt_csv_columns]` can map
+const OPTIONAL_COLUMNS: [&str; 7] = ["user_txn_id", "notes", "venue", "fee_amount", "fee_asset", "fee_value", "tx_hash"];
+
 pub fn build_config(config_filepath: PathBuf) -> Result<Config, ConfigParseError> {
     let mut config = Config::default();
 
     let ini_file = Ini::load_from_file(config_filepath).map_err(ConfigParseError::IniLoadError)?;
 
-    for (index, section) in ini_file.sections().enumerate() {
+    for (section, properties) in ini_file.iter() {
         match section {
             Some("accounting_type") => {
-                let accounting_str = &ini_file[section]["accounting_type"];
-                config.accounting_type = match AccountingType::match_accounting_type(accounting_str)
-                {
-                    Some(a) => a,
-                    None => panic!("Cannot match accounting type")
-                };
+                let accounting_str = required(properties, "accounting_type", "accounting_type")?;
+                config.accounting_type = AccountingType::match_accounting_type(accounting_str)
+                    .ok_or_else(|| invalid_value("accounting_type", "accounting_type", accounting_str))?;
             }
             Some("file_info") => {
-                let dir = &ini_file[section]["dir"];
-                let filename = &ini_file[section]["filename"];
+                let dir = required(properties, "file_info", "dir")?;
+                let filename = required(properties, "file_info", "filename")?;
 
                 if dir.is_empty() || filename.is_empty() {
                     return Err(ConfigParseError::FilepathError);
                 }
 
                 config.filepath = PathBuf::from(dir).join(filename);
+
+                if let Some(format) = properties.get("format") {
+                    config.file_format = FileFormat::match_format(format.trim()).ok_or_else(|| invalid_value("file_info", "format", format))?;
+                }
             }
             Some("csv_columns") => {
-                config.csv_columns = get_map_and_swap(&ini_file[section]);
+                config.csv_columns.extend(get_map_and_swap(properties));
+            }
+            Some("opt_csv_columns") => {
+                // <field> = <csv header>, for the optional fields only
+                for (field, header) in properties.iter() {
+                    if !OPTIONAL_COLUMNS.contains(&field) {
+                        return Err(invalid_value("opt_csv_columns", field, header));
+                    }
+                    config.csv_columns.insert(header.to_string(), field.to_string());
+                }
             }
             Some("buy_txn_types") => {
-                config.buy_txn_types = string_to_vec(&ini_file[section]["buys"]);
+                config.buy_txn_types = string_to_vec(required(properties, "buy_txn_types", "buys")?);
             }
             Some("sell_txn_types") => {
-                config.sell_txn_types = string_to_vec(&ini_file[section]["sells"]);
+                config.sell_txn_types = string_to_vec(required(properties, "sell_txn_types", "sells")?);
+            }
+            Some("income_txn_types") => {
+                // <kind> = <txn types>, eg staking = STAKING, STAKING REWARD
+                for (kind, value) in properties.iter() {
+                    let income_kind = IncomeKind::match_income_kind(kind.trim())
+                        .ok_or_else(|| invalid_value("income_txn_types", kind, value))?;
+                    config.income_txn_types.push((income_kind, string_to_vec(value)));
+                }
+            }
+            Some("transfer_txn_types") => {
+                if let Some(deposits) = properties.get("deposits") {
+                    config.transfers.deposit_txn_types = string_to_vec(deposits);
+                }
+                if let Some(withdrawals) = properties.get("withdrawals") {
+                    config.transfers.withdrawal_txn_types = string_to_vec(withdrawals);
+                }
+                if let Some(tolerance) = properties.get("amount_tolerance") {
+                    config.transfers.amount_tolerance = tolerance
+                        .trim()
+                        .parse()
+                        .map_err(|_| invalid_value("transfer_txn_types", "amount_tolerance", tolerance))?;
+                }
+                if let Some(hours) = properties.get("time_window_hours") {
+                    config.transfers.time_window_hours = hours
+                        .trim()
+                        .parse()
+                        .map_err(|_| invalid_value("transfer_txn_types", "time_window_hours", hours))?;
+                }
+            }
+            Some("ordering") => {
+                if let Some(tie_breaker) = properties.get("tie_breaker") {
+                    config.tie_breaker = TieBreaker::match_tie_breaker(tie_breaker.trim())
+                        .ok_or_else(|| invalid_value("ordering", "tie_breaker", tie_breaker))?;
+                }
+            }
+            Some("swaps") => {
+                if let Some(tolerance) = properties.get("tolerance") {
+                    config.swaps.tolerance = tolerance.trim().parse().map_err(|_| invalid_value("swaps", "tolerance", tolerance))?;
+                }
+                if let Some(fair_value) = properties.get("fair_value") {
+                    config.swaps.fair_value = SwapValuation::match_valuation(fair_value.trim())
+                        .ok_or_else(|| invalid_value("swaps", "fair_value", fair_value))?;
+                }
+            }
+            Some("holding_period") => {
+                if let Some(months) = properties.get("long_term_months") {
+                    config.holding_period.long_term_months = months
+                        .trim()
+                        .parse()
+                        .map_err(|_| invalid_value("holding_period", "long_term_months", months))?;
+                }
+            }
+            Some("specific_id") => {
+                let dir = properties.get("dir").unwrap_or_default();
+                let filename = properties.get("filename").unwrap_or_default();
+
+                if filename.is_empty() {
+                    return Err(ConfigParseError::FilepathError);
+                }
+                config.specific_id.filepath = PathBuf::from(dir).join(filename);
+
+                if let Some(method) = properties.get("default_method") {
+                    config.specific_id.default_method = match AccountingType::match_accounting_type(method) {
+                        Some(AccountingType::ACB | AccountingType::UkPooling | AccountingType::SpecificId) | None => {
+                            return Err(invalid_value("specific_id", "default_method", method))
+                        }
+                        Some(a) => a,
+                    };
+                }
             }
-            // Some("venues") => {
-            //     let col_name: String = String::from(&ini_file[section]["column_name"]);
-            //     config.csv_columns.insert(col_name, "venue".to_string());
-            //     config.venues = Some(string_to_vec(&ini_file[section]["venues"]));
-            // }
-            None => {
-                // Ignore General Section, which is always returned first (i=0)
-                if index != 0 {
-                    panic!("Uncharacterized Error, attempted import on None type on config file");
+            Some("price_source") => {
+                let dir = properties.get("dir").unwrap_or_default();
+                let filename = required(properties, "price_source", "filename")?;
+                config.price_source.filepath = PathBuf::from(dir).join(filename);
+
+                if let Some(currency) = properties.get("currency") {
+                    config.price_source.currency = currency.trim().to_string();
                 }
             }
-            _ => {
-                println!("Attempt to import unknown section: {}", section.unwrap());
+            Some("fx_rates") => {
+                if let Some(currency) = properties.get("reporting_currency") {
+                    config.reporting_currency = currency.trim().to_string();
+                }
+                if let Some(filename) = properties.get("filename") {
+                    config.fx_rates = PathBuf::from(properties.get("dir").unwrap_or_default()).join(filename);
+                }
+            }
+            Some("opening_lots") => {
+                let filename = required(properties, "opening_lots", "filename")?;
+                config.opening_lots = PathBuf::from(properties.get("dir").unwrap_or_default()).join(filename);
+            }
+            Some("open_lots") => {
+                if let Some(as_of) = properties.get("as_of") {
+                    config.open_lots_as_of = Some(parse_datetime_string(as_of.trim()).map_err(|_| invalid_value("open_lots", "as_of", as_of))?);
+                }
+            }
+            Some("safe_harbor") => {
+                let dir = properties.get("dir").unwrap_or_default();
+                let filename = required(properties, "safe_harbor", "filename")?;
+                let cutover = required(properties, "safe_harbor", "cutover")?;
+
+                config.safe_harbor = Some(SafeHarborConfig {
+                    filepath: PathBuf::from(dir).join(filename),
+                    cutover: parse_datetime_string(cutover.trim()).map_err(|_| invalid_value("safe_harbor", "cutover", cutover))?,
+                });
+            }
+            Some("tax_rates") => {
+                for (key, rate) in [("short_term", &mut config.tax_rates.short_term), ("long_term", &mut config.tax_rates.long_term)] {
+                    if let Some(value) = properties.get(key) {
+                        *rate = value.trim().parse().map_err(|_| invalid_value("tax_rates", key, value))?;
+                    }
+                }
+            }
+            Some("insufficient_inventory") => {
+                if let Some(policy) = properties.get("policy") {
+                    config.inventory.policy = ShortfallPolicy::match_policy(policy.trim())
+                        .ok_or_else(|| invalid_value("insufficient_inventory", "policy", policy))?;
+                }
+            }
+            Some("import") => {
+                if let Some(policy) = properties.get("on_error") {
+                    config.import_errors = ImportErrorPolicy::match_policy(policy.trim())
+                        .ok_or_else(|| invalid_value("import", "on_error", policy))?;
+                }
+            }
+            Some("opening_balance") => {
+                // <asset> = <amount>, <total cost basis>, <acquisition date>
+                for (asset, value) in properties.iter() {
+                    let opening_balance = parse_opening_balance(value)
+                        .ok_or_else(|| invalid_value("opening_balance", asset, value))?;
+                    config.inventory.opening_balances.insert(asset.to_string(), opening_balance);
+                }
+            }
+            Some("rounding") => {
+                if let Some(places) = properties.get("decimal_places") {
+                    config.rounding.decimal_places = places
+                        .trim()
+                        .parse()
+                        .map_err(|_| invalid_value("rounding", "decimal_places", places))?;
+                }
+                if let Some(strategy) = properties.get("strategy") {
+                    config.rounding.strategy = Rounding::match_strategy(strategy.trim())
+                        .ok_or_else(|| invalid_value("rounding", "strategy", strategy))?;
+                }
+            }
+            Some("venues") => {
+                let col_name = required(properties, "venues", "column_name")?;
+                config.csv_columns.insert(col_name.to_string(), "venue".to_string());
+                config.venues = Some(properties.get("venues").map(string_to_vec).unwrap_or_default());
+            }
+            Some(section) if section.starts_with("txn_rule.") => {
+                config.txn_rules.push(parse_txn_rule(section, properties)?);
+            }
+            None => {} // General section, keys outside any [section]
+            Some(unknown) => {
+                println!("Attempt to import unknown section: {}", unknown);
             }
         }
     }
+    config.txn_rules.sort_by_key(|rule| rule.precedence); // Stable, so config order breaks ties
+
+    if config.safe_harbor.is_some() && config.venues.is_none() {
+        return Err(ConfigParseError::MissingSection("venues".to_string()));
+    }
+    if matches!(config.accounting_type, AccountingType::SpecificId) && config.specific_id.filepath.as_os_str().is_empty() {
+        return Err(ConfigParseError::MissingSection("specific_id".to_string()));
+    }
+
     Ok(config)
 }
 
@@ -107,6 +610,61 @@ pub fn build_config(config_filepath: PathBuf) -> Result<Config, ConfigParseError
 //         .collect()
 // }
 
+/// Value of a key the section can't do without
+fn required<'a>(properties: &'a ini::Properties, section: &str, key: &str) -> Result<&'a str, ConfigParseError> {
+    properties.get(key).ok_or_else(|| ConfigParseError::MissingKey {
+        section: section.to_string(),
+        key: key.to_string(),
+    })
+}
+
+fn invalid_value(section: &str, key: &str, value: &str) -> ConfigParseError {
+    ConfigParseError::InvalidValue {
+        section: section.to_string(),
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+/// [txn_rule.<name>] with txn_type & pattern, and optional match, asset, notes, amount_sign & precedence
+fn parse_txn_rule(section: &str, properties: &ini::Properties) -> Result<TxnRule, ConfigParseError> {
+    let txn_type = required(properties, section, "txn_type")?;
+    let pattern = required(properties, section, "pattern")?;
+    let match_kind = properties.get("match").unwrap_or("exact");
+
+    Ok(TxnRule {
+        name: section.trim_start_matches("txn_rule.").to_string(),
+        txn_type: TxnType::match_rule_txn_type(txn_type.trim()).ok_or_else(|| invalid_value(section, "txn_type", txn_type))?,
+        pattern: TxnPattern::new(match_kind.trim(), pattern).ok_or_else(|| invalid_value(section, "pattern", pattern))?,
+        assets: properties.get("asset").map(string_to_vec).unwrap_or_default(),
+        notes: properties
+            .get("notes")
+            .map(|notes| Regex::new(notes).map_err(|_| invalid_value(section, "notes", notes)))
+            .transpose()?,
+        amount_sign: properties
+            .get("amount_sign")
+            .map(|sign| AmountSign::match_amount_sign(sign.trim()).ok_or_else(|| invalid_value(section, "amount_sign", sign)))
+            .transpose()?,
+        precedence: properties
+            .get("precedence")
+            .map(|precedence| precedence.trim().parse().map_err(|_| invalid_value(section, "precedence", precedence)))
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+fn parse_opening_balance(value: &str) -> Option<OpeningBalance> {
+    let fields = string_to_vec(value);
+    match fields.as_slice() {
+        [amount, cost_basis, acquired] => Some(OpeningBalance {
+            amount: amount.parse().ok()?,
+            cost_basis: cost_basis.parse().ok()?,
+            acquired: parse_datetime_string(acquired).ok()?,
+        }),
+        _ => None,
+    }
+}
+
 fn string_to_vec(string_: &str) -> Vec<String> {
 
     string_
@@ -0,0 +1,39 @@
+//! Reading the small side files, eg lot assignments, wallet balances, starting lots and price histories.
+//! Each is a csv with a header row, read by column name into a record of strings that the caller parses
+
+use csv::ReaderBuilder;
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::trade::{FieldError, ValueParseError};
+
+
+/// Reads every row of `filepath` into `T`, with cells trimmed, and turns it into `U` with `parse`.
+/// A row `parse` rejects is an error at its line in the file
+pub fn read_records<T, U>(filepath: &Path, mut parse: impl FnMut(T) -> Result<U, FieldError>) -> Result<Vec<U>, CryptotaxError>
+where
+    T: DeserializeOwned,
+{
+    let csv_error = |source: csv::Error| CryptotaxError::Csv { filepath: filepath.to_path_buf(), source };
+    let mut rdr = ReaderBuilder::new().has_headers(true).trim(csv::Trim::All).from_path(filepath).map_err(csv_error)?;
+    let headers = rdr.headers().map_err(csv_error)?.to_owned();
+
+    let mut parsed: Vec<U> = vec![];
+    for result in rdr.records() {
+        let row = result.map_err(csv_error)?;
+        let line = row.position().map(|position| position.line()).unwrap_or_default();
+        let record: T = row.deserialize(Some(&headers)).map_err(csv_error)?;
+
+        parsed.push(parse(record).map_err(|source| CryptotaxError::Record { filepath: filepath.to_path_buf(), line, source })?);
+    }
+    Ok(parsed)
+}
+
+
+/// Parses a decimal cell, naming its column on failure
+pub fn parse_decimal(column: &str, value: &str) -> Result<Decimal, FieldError> {
+    value.parse::<Decimal>()
+        .map_err(|e| FieldError::new(column, ValueParseError::DecimalParseError { value: value.to_string(), source: e }))
+}
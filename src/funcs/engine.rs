@@ -0,0 +1,122 @@
+//! Library facade that runs the full import -> match -> summarise pipeline
+
+use chrono::NaiveDateTime;
+use polars::prelude::DataFrame;
+use std::path::{Path, PathBuf};
+
+use crate::funcs::config::{build_config, Config, ConfigParseError};
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::import_trades::{import_trades, import_trades_with_errors, ImportError, SortedTrades};
+use crate::funcs::income::{get_income_events, get_income_summary, IncomeEvent};
+use crate::funcs::inventory::Shortfall;
+use crate::funcs::open_lots::{get_open_lot_report, OpenLot};
+use crate::funcs::process_trades::{calc_cost_basis, get_annual_summary, match_trades, process_trades, CostBases, OpenLots, SaleEvent};
+use crate::funcs::safe_harbor::{allocate_lots, import_wallet_balances};
+use crate::funcs::starting_lots::StartingLot;
+use crate::funcs::transfers::{match_transfers, TransferReport};
+use crate::funcs::txn_type::{get_unclassified_txn_types, UnclassifiedTxnType};
+use crate::funcs::uk_pooling::PoolSnapshot;
+
+
+/// Output of a single engine run
+#[derive(Debug, Clone)]
+pub struct TaxReport {
+    pub sale_events: Vec<SaleEvent>,
+    pub open_lots: OpenLots,
+    pub open_lot_report: Vec<OpenLot>, // Open lots with holding periods as of `Config::open_lots_as_of`
+    pub cost_bases: CostBases,
+    pub annual_summary: DataFrame,
+    pub pool_snapshots: Vec<PoolSnapshot>, // Section 104 pool at each 5 April. Only for UK pooling
+    pub shortfalls: Vec<Shortfall>, // Sales that exceeded the available holdings
+    pub import_errors: Vec<ImportError>, // Rows skipped under `ImportErrorPolicy::Skip`
+    pub income_events: Vec<IncomeEvent>,
+    pub income_summary: DataFrame, // Ordinary income per kind and year
+    pub transfers: TransferReport, // Paired and unpaired deposits / withdrawals
+    pub unclassified_txn_types: Vec<UnclassifiedTxnType>, // Raw types that fell through to Other
+}
+
+
+/// Entry point for embedding the engine. Holds a `Config` and processes trades against it
+#[derive(Debug)]
+pub struct TaxEngine {
+    config: Config,
+}
+
+impl TaxEngine {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Builds an engine from an INI config file
+    pub fn from_config_file(config_filepath: PathBuf) -> Result<Self, ConfigParseError> {
+        Ok(Self::new(build_config(config_filepath)?))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Imports trades from the csv file referenced by the config and processes them
+    pub fn run(&self) -> Result<TaxReport, CryptotaxError> {
+        let (trades, import_errors) = import_trades_with_errors(&self.config)?;
+        let mut report = self.process(trades)?;
+        report.import_errors = import_errors;
+        Ok(report)
+    }
+
+    /// Processes trades that were already imported, keyed by base asset
+    pub fn process(&self, trades: SortedTrades) -> Result<TaxReport, CryptotaxError> {
+        let unclassified_txn_types = get_unclassified_txn_types(&trades);
+        let income_events = get_income_events(&trades);
+        let income_summary = get_income_summary(&income_events, &self.config.rounding)?;
+
+        let as_of = self.config.open_lots_as_of
+            .or_else(|| trades.values().flatten().map(|trade| trade.trade_time).max())
+            .unwrap_or_default();
+
+        let processed = process_trades(trades, &self.config)?;
+        let (sale_events, open_lots, shortfalls, pool_snapshots, transfers) =
+            (processed.sale_events, processed.open_lots, processed.shortfalls, processed.pool_snapshots, processed.transfers);
+
+        let cost_bases: CostBases = open_lots
+            .iter()
+            .map(|(asset, lots)| (asset.to_owned(), calc_cost_basis(lots)))
+            .collect();
+
+        let annual_summary = get_annual_summary(&sale_events, &self.config.rounding)?;
+        let open_lot_report = get_open_lot_report(&open_lots, as_of, self.config.holding_period.long_term_months);
+
+        Ok(TaxReport {
+            sale_events,
+            open_lots,
+            open_lot_report,
+            cost_bases,
+            annual_summary,
+            pool_snapshots,
+            shortfalls,
+            import_errors: vec![],
+            income_events,
+            income_summary,
+            transfers,
+            unclassified_txn_types,
+        })
+    }
+
+    /// Allocates the lots open just before `cutover`, tracked without venues, to the wallet balances
+    /// in `balances_filepath`. The result is the starting state for per-venue processing from the cut-over
+    pub fn safe_harbor_allocation(&self, cutover: NaiveDateTime, balances_filepath: &Path) -> Result<Vec<StartingLot>, CryptotaxError> {
+        let mut trades = import_trades(&self.config)?;
+        match_transfers(&mut trades, &self.config);
+
+        for asset_trades in trades.values_mut() {
+            asset_trades.retain(|trade| trade.trade_time < cutover);
+        }
+        trades.retain(|_, asset_trades| !asset_trades.is_empty());
+
+        let universal = Config { venues: None, safe_harbor: None, ..self.config.clone() };
+        let processed = match_trades(trades, &universal)?;
+
+        let balances = import_wallet_balances(balances_filepath)?;
+        Ok(allocate_lots(&processed.open_lots, &balances)?)
+    }
+}
@@ -0,0 +1,47 @@
+//! Crate-level error, so an embedding service can surface every failure instead of crashing
+
+use polars::prelude::PolarsError;
+use std::path::PathBuf;
+
+use crate::funcs::config::ConfigParseError;
+use crate::funcs::import_trades::ImportError;
+use crate::funcs::inventory::InventoryError;
+use crate::funcs::price_source::PriceError;
+use crate::funcs::safe_harbor::SafeHarborError;
+use crate::funcs::swaps::SwapError;
+use crate::funcs::specific_id::LotAssignmentError;
+use crate::funcs::trade::FieldError;
+
+
+#[derive(thiserror::Error, Debug)]
+pub enum CryptotaxError {
+    #[error(transparent)]
+    Config(#[from] ConfigParseError),
+    #[error("ERROR: Could not read {}.\nMSG: {source}", filepath.display())]
+    Csv {
+        filepath: PathBuf,
+        #[source]
+        source: csv::Error,
+    },
+    #[error("ERROR: {}, line {line}, {source}", filepath.display())]
+    Record {
+        filepath: PathBuf,
+        line: u64,
+        #[source]
+        source: FieldError,
+    },
+    #[error("ERROR: {} rows could not be imported, see the import errors report", .0.len())]
+    InvalidRows(Vec<ImportError>),
+    #[error(transparent)]
+    Price(#[from] PriceError),
+    #[error(transparent)]
+    LotAssignment(#[from] LotAssignmentError),
+    #[error(transparent)]
+    Inventory(#[from] InventoryError),
+    #[error(transparent)]
+    SafeHarbor(#[from] SafeHarborError),
+    #[error(transparent)]
+    Swap(#[from] SwapError),
+    #[error("ERROR: Could not build report.\nMSG: {0}")]
+    Report(#[from] PolarsError),
+}
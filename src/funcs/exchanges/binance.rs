@@ -0,0 +1,70 @@
+//! Binance spot trade history. Newer exports glue each amount to its asset, eg "0.5BTC"; older ones
+//! have a Market column and plain amounts with a separate fee coin
+
+use rust_decimal::Decimal;
+
+use crate::funcs::config::Config;
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::exchanges::{export_rows, format_error, read_export, split_amount_asset, split_pair, ExportRow};
+use crate::funcs::import_trades::{CsvRecord, ImportedRow};
+use crate::funcs::price_source::PriceHistory;
+use crate::funcs::trade::{parse_datetime_string, FieldError, Trade};
+use crate::funcs::txn_type::TxnType;
+
+
+const VENUE: &str = "binance";
+
+
+pub fn import_rows(config: &Config, price_history: Option<&PriceHistory>) -> Result<Vec<ImportedRow>, CryptotaxError> {
+    let (headers, rows) = read_export(&config.filepath, "Date(UTC)")?;
+    Ok(export_rows(&headers, rows).map(|row| (row.line, row_trade(&row, config, price_history))).collect())
+}
+
+
+fn row_trade(row: &ExportRow, config: &Config, price_history: Option<&PriceHistory>) -> Result<Trade, FieldError> {
+    let pair = row.get(&["Pair", "Market"]);
+    let (base_asset, quote_asset) = split_pair(&pair)
+        .ok_or_else(|| format_error("Pair", format!("Unrecognised pair {}", pair)))?;
+
+    let amount_asset = |column: &str| split_amount_asset(&row.get(&[column]), &[&base_asset, &quote_asset]).map_err(|e| FieldError::new(column, e));
+    let (base_asset_amount, quote_asset_amount, (fee_amount, fee_asset)) = match row.get(&["Executed"]).is_empty() {
+        true => (row.amount(&["Amount"])?, row.amount(&["Total"])?, (row.amount(&["Fee"])?, row.get(&["Fee Coin"]))),
+        false => (amount_asset("Executed")?.0, amount_asset("Amount")?.0, amount_asset("Fee")?),
+    };
+
+    let raw_type = row.get(&["Side", "Type"]);
+    let txn_type = match raw_type.to_uppercase().as_str() {
+        "BUY" => Some(TxnType::Buy),
+        "SELL" => Some(TxnType::Sale),
+        _ => None,
+    };
+
+    // Fees paid in a third asset, usually BNB, are valued from the price history when it has both prices
+    let timestamp = row.get(&["Date(UTC)"]);
+    let fee_value = match fee_asset != base_asset && fee_asset != quote_asset {
+        true => price_history.zip(parse_datetime_string(&timestamp).ok()).and_then(|(history, time)| {
+            let fee_price = history.price_at(&fee_asset, time)?;
+            let quote_price = match quote_asset == config.price_source.currency {
+                true => Decimal::ONE,
+                false => history.price_at(&quote_asset, time)?,
+            };
+            (fee_amount * fee_price).checked_div(quote_price)
+        }),
+        false => None,
+    };
+
+    let record = CsvRecord {
+        timestamp,
+        txn_type: raw_type,
+        base_asset,
+        base_asset_amount: base_asset_amount.to_string(),
+        quote_asset,
+        quote_asset_amount: quote_asset_amount.to_string(),
+        fee_amount: Some(fee_amount.to_string()),
+        fee_asset: Some(fee_asset),
+        fee_value: fee_value.map(|value| value.to_string()),
+        venue: Some(VENUE.to_string()),
+        ..Default::default()
+    };
+    Trade::with_txn_type(record, txn_type, config)
+}
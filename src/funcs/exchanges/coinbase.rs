@@ -0,0 +1,109 @@
+//! Coinbase transaction history report. Amounts are signed, and Convert rows carry both sides in their notes
+
+use rust_decimal::Decimal;
+
+use crate::funcs::config::Config;
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::exchanges::{export_rows, format_error, parse_amount, read_export, ExportRow};
+use crate::funcs::import_trades::{CsvRecord, ImportedRow};
+use crate::funcs::price_source::is_fiat;
+use crate::funcs::trade::{FieldError, Trade};
+use crate::funcs::txn_type::{IncomeKind, TxnType};
+
+
+const VENUE: &str = "coinbase";
+
+
+pub fn import_rows(config: &Config) -> Result<Vec<ImportedRow>, CryptotaxError> {
+    let (headers, rows) = read_export(&config.filepath, "Transaction Type")?;
+
+    let mut imported: Vec<ImportedRow> = vec![];
+    for row in export_rows(&headers, rows) {
+        match row_trades(&row, config) {
+            Ok(trades) => imported.extend(trades.into_iter().map(|trade| (row.line, Ok(trade)))),
+            Err(e) => imported.push((row.line, Err(e))),
+        }
+    }
+    Ok(imported)
+}
+
+
+fn row_trades(row: &ExportRow, config: &Config) -> Result<Vec<Trade>, FieldError> {
+    let raw_type = row.get(&["Transaction Type"]);
+    let asset = row.get(&["Asset"]);
+    let quantity = row.amount(&["Quantity Transacted"])?.abs();
+    let currency = row.get(&["Price Currency", "Spot Price Currency"]);
+    let price = row.amount(&["Price at Transaction", "Spot Price at Transaction"])?;
+
+    // Older reports leave the subtotal empty on rewards
+    let subtotal = match row.amount(&["Subtotal"])?.abs() {
+        subtotal if subtotal.is_zero() => quantity * price,
+        subtotal => subtotal,
+    };
+
+    let record = CsvRecord {
+        user_txn_id: Some(row.get(&["ID"])),
+        timestamp: row.get(&["Timestamp"]).trim_end_matches(" UTC").to_string(),
+        txn_type: raw_type.to_owned(),
+        base_asset: asset.to_owned(),
+        base_asset_amount: quantity.to_string(),
+        quote_asset: currency.to_owned(),
+        quote_asset_amount: subtotal.to_string(),
+        fee_amount: Some(row.amount(&["Fees and/or Spread", "Fees"])?.abs().to_string()),
+        venue: Some(VENUE.to_string()),
+        notes: Some(row.get(&["Notes"])),
+        ..Default::default()
+    };
+
+    let txn_type = match raw_type.as_str() {
+        "Convert" => return convert_legs(record, config),
+        _ if is_fiat(&asset) => Some(TxnType::Other),
+        "Buy" | "Advanced Trade Buy" => Some(TxnType::Buy),
+        "Sell" | "Advanced Trade Sell" => Some(TxnType::Sale),
+        "Send" => Some(TxnType::Withdrawal),
+        "Receive" => Some(TxnType::Deposit),
+        "Rewards Income" | "Staking Income" | "Inflation Reward" => Some(TxnType::Income(IncomeKind::Staking)),
+        "Learning Reward" | "Coinbase Earn" => Some(TxnType::Income(IncomeKind::Airdrop)),
+        _ => None,
+    };
+    Ok(vec![Trade::with_txn_type(record, txn_type, config)?])
+}
+
+
+/// A Convert row is a swap, eg "Converted 0.01 BTC to 0.15 ETH". Both legs share the row's ID, so
+/// `link_swaps` pairs them, and take its subtotal as their value
+fn convert_legs(record: CsvRecord, config: &Config) -> Result<Vec<Trade>, FieldError> {
+    let notes = record.notes.to_owned().unwrap_or_default();
+    let (given, received) = notes.strip_prefix("Converted ")
+        .and_then(|notes| notes.split_once(" to "))
+        .ok_or_else(|| format_error("Notes", format!("Convert notes \"{}\" don't name both assets", notes)))?;
+
+    let leg = |side: &str| -> Result<(Decimal, String), FieldError> {
+        let (amount, asset) = side.trim().split_once(' ')
+            .ok_or_else(|| format_error("Notes", format!("Convert notes \"{}\" don't name both assets", notes)))?;
+        Ok((parse_amount(amount).map_err(|e| FieldError::new("Notes", e))?, asset.trim().to_string()))
+    };
+    let ((given_amount, given_asset), (received_amount, received_asset)) = (leg(given)?, leg(received)?);
+
+    let sale = CsvRecord {
+        base_asset: given_asset,
+        base_asset_amount: given_amount.to_string(),
+        ..record
+    };
+    let buy = CsvRecord {
+        user_txn_id: sale.user_txn_id.to_owned(),
+        timestamp: sale.timestamp.to_owned(),
+        txn_type: sale.txn_type.to_owned(),
+        base_asset: received_asset,
+        base_asset_amount: received_amount.to_string(),
+        quote_asset: sale.quote_asset.to_owned(),
+        quote_asset_amount: sale.quote_asset_amount.to_owned(),
+        venue: sale.venue.to_owned(),
+        ..Default::default()
+    };
+
+    Ok(vec![
+        Trade::with_txn_type(sale, Some(TxnType::Sale), config)?,
+        Trade::with_txn_type(buy, Some(TxnType::Buy), config)?,
+    ])
+}
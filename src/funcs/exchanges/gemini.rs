@@ -0,0 +1,81 @@
+//! Gemini transaction history. Each asset has its own amount and fee columns, eg "BTC Amount BTC",
+//! and negative amounts are in parentheses
+
+use rust_decimal::Decimal;
+
+use crate::funcs::config::Config;
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::exchanges::{export_rows, format_error, income_value, read_export, split_pair, ExportRow};
+use crate::funcs::import_trades::{CsvRecord, ImportedRow};
+use crate::funcs::price_source::{is_fiat, PriceHistory};
+use crate::funcs::trade::{FieldError, Trade};
+use crate::funcs::txn_type::{IncomeKind, TxnType};
+
+
+const VENUE: &str = "gemini";
+
+
+pub fn import_rows(config: &Config, price_history: Option<&PriceHistory>) -> Result<Vec<ImportedRow>, CryptotaxError> {
+    let (headers, rows) = read_export(&config.filepath, "Specification")?;
+
+    // The export ends with a totals row, which has no date
+    Ok(export_rows(&headers, rows)
+        .filter(|row| !row.get(&["Date"]).is_empty())
+        .map(|row| (row.line, row_trade(&row, config, price_history)))
+        .collect())
+}
+
+
+fn amount_column(asset: &str) -> String {
+    format!("{} Amount {}", asset, asset)
+}
+
+
+fn row_trade(row: &ExportRow, config: &Config, price_history: Option<&PriceHistory>) -> Result<Trade, FieldError> {
+    let raw_type = row.get(&["Type"]);
+    let symbol = row.get(&["Symbol"]);
+    let specification = row.get(&["Specification"]);
+
+    let (base_asset, quote_asset) = match raw_type.as_str() {
+        "Buy" | "Sell" => split_pair(&symbol).ok_or_else(|| format_error("Symbol", format!("Unrecognised symbol {}", symbol)))?,
+        _ => (symbol.to_owned(), symbol.to_owned()),
+    };
+
+    let txn_type = match raw_type.as_str() {
+        "Buy" => Some(TxnType::Buy),
+        "Sell" => Some(TxnType::Sale),
+        "Credit" | "Debit" if is_fiat(&base_asset) => Some(TxnType::Other),
+        "Credit" if specification.contains("Interest") => Some(TxnType::Income(IncomeKind::Interest)),
+        "Credit" if specification.contains("Staking") || specification.contains("Reward") => Some(TxnType::Income(IncomeKind::Staking)),
+        "Credit" => Some(TxnType::Deposit),
+        "Debit" => Some(TxnType::Withdrawal),
+        _ => None,
+    };
+
+    let timestamp = format!("{} {}", row.get(&["Date"]), row.get(&["Time (UTC)"])).trim().to_string();
+    let base_asset_amount = row.amount(&[&amount_column(&base_asset)])?.abs();
+
+    // Transfers and rewards only have an amount in the asset, so rewards are valued from the price history
+    let (quote_asset, quote_asset_amount) = match txn_type {
+        Some(TxnType::Buy | TxnType::Sale) => (quote_asset.to_owned(), row.amount(&[&amount_column(&quote_asset)])?.abs()),
+        Some(TxnType::Income(_)) => (config.price_source.currency.to_owned(), income_value(&base_asset, base_asset_amount, &timestamp, price_history)?),
+        _ => (quote_asset, Decimal::ZERO),
+    };
+    let fee_column = format!("Trading Fee ({}) {}", quote_asset, quote_asset);
+
+    let record = CsvRecord {
+        user_txn_id: Some(row.get(&["Trade ID"])),
+        timestamp,
+        txn_type: raw_type,
+        base_asset_amount: base_asset_amount.to_string(),
+        base_asset,
+        quote_asset,
+        quote_asset_amount: quote_asset_amount.to_string(),
+        fee_amount: Some(row.amount(&[&fee_column])?.abs().to_string()),
+        venue: Some(VENUE.to_string()),
+        notes: Some(specification),
+        tx_hash: Some(row.get(&["Tx Hash"])),
+        ..Default::default()
+    };
+    Trade::with_txn_type(record, txn_type, config)
+}
@@ -0,0 +1,188 @@
+//! Kraken ledgers or trades export. A ledger trade is a pair of rows sharing a refid, one per asset
+
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+
+use crate::funcs::config::Config;
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::exchanges::{export_rows, format_error, income_value, read_export, split_pair, ExportRow};
+use crate::funcs::import_trades::{CsvRecord, ImportedRow};
+use crate::funcs::price_source::{is_fiat, PriceHistory};
+use crate::funcs::trade::{FieldError, Trade};
+use crate::funcs::txn_type::{IncomeKind, TxnType};
+
+
+const VENUE: &str = "kraken";
+
+
+/// Reads either export. The trades export is the one with a pair column
+pub fn import_rows(config: &Config, price_history: Option<&PriceHistory>) -> Result<Vec<ImportedRow>, CryptotaxError> {
+    let (headers, rows) = read_export(&config.filepath, "txid")?;
+    let rows: Vec<ExportRow> = export_rows(&headers, rows).collect();
+
+    if headers.iter().any(|header| header.trim() == "pair") {
+        return Ok(rows.iter().map(|row| (row.line, trade_row(row, config))).collect());
+    }
+
+    // Trade legs are gathered by refid, everything else stands alone
+    let mut imported: Vec<ImportedRow> = vec![];
+    let mut trade_legs: BTreeMap<String, Vec<&ExportRow>> = BTreeMap::new();
+    for row in &rows {
+        match row.get(&["type"]).as_str() {
+            "trade" | "spend" | "receive" => trade_legs.entry(row.get(&["refid"])).or_default().push(row),
+            _ => imported.push((row.line, ledger_row(row, config, price_history))),
+        }
+    }
+    for (refid, legs) in trade_legs {
+        imported.push((legs[0].line, ledger_trade(&refid, &legs, config)));
+    }
+
+    imported.sort_by_key(|(line, _)| *line);
+    Ok(imported)
+}
+
+
+/// Kraken's asset codes to the usual tickers. Staked and other wallet variants, eg "DOT.S", count as the asset
+pub fn normalize_asset(asset: &str) -> String {
+    let asset = asset.split('.').next().unwrap_or_default();
+    match asset {
+        "XXBT" | "XBT" => "BTC",
+        "XETH" | "ETH2" => "ETH",
+        "XXDG" | "XDG" => "DOGE",
+        "XXRP" => "XRP",
+        "XLTC" => "LTC",
+        "XXLM" => "XLM",
+        "XETC" => "ETC",
+        "XZEC" => "ZEC",
+        "XXMR" => "XMR",
+        "XREP" => "REP",
+        "XMLN" => "MLN",
+        "ZUSD" => "USD",
+        "ZEUR" => "EUR",
+        "ZGBP" => "GBP",
+        "ZCAD" => "CAD",
+        "ZJPY" => "JPY",
+        "ZAUD" => "AUD",
+        "ZCHF" => "CHF",
+        other => other,
+    }.to_string()
+}
+
+
+/// Splits a pair, including the old eight-letter form like "XXBTZUSD"
+fn split_kraken_pair(pair: &str) -> Option<(String, String)> {
+    let (base, quote) = match pair.is_ascii() && pair.len() == 8 && pair.starts_with('X') && matches!(&pair[4..5], "X" | "Z") {
+        true => (pair[..4].to_string(), pair[4..].to_string()),
+        false => split_pair(pair)?,
+    };
+    Some((normalize_asset(&base), normalize_asset(&quote)))
+}
+
+
+fn trade_row(row: &ExportRow, config: &Config) -> Result<Trade, FieldError> {
+    let pair = row.get(&["pair"]);
+    let (base_asset, quote_asset) = split_kraken_pair(&pair)
+        .ok_or_else(|| format_error("pair", format!("Unrecognised pair {}", pair)))?;
+
+    let raw_type = row.get(&["type"]);
+    let txn_type = match raw_type.as_str() {
+        "buy" => Some(TxnType::Buy),
+        "sell" => Some(TxnType::Sale),
+        _ => None,
+    };
+    let record = CsvRecord {
+        user_txn_id: Some(row.get(&["txid"])),
+        timestamp: row.get(&["time"]),
+        txn_type: raw_type,
+        base_asset,
+        base_asset_amount: row.amount(&["vol"])?.to_string(),
+        quote_asset,
+        quote_asset_amount: row.amount(&["cost"])?.to_string(),
+        fee_amount: Some(row.amount(&["fee"])?.to_string()),
+        venue: Some(VENUE.to_string()),
+        ..Default::default()
+    };
+    Trade::with_txn_type(record, txn_type, config)
+}
+
+
+/// One trade from the ledger rows of a refid: the asset given up and the asset received.
+/// A fiat side makes it a buy or sale against that currency, otherwise it is a buy quoted in the asset given
+fn ledger_trade(refid: &str, legs: &[&ExportRow], config: &Config) -> Result<Trade, FieldError> {
+    let missing_leg = || format_error("refid", format!("Trade {} doesn't have both a spent and a received row", refid));
+    let given = legs.iter().find(|leg| leg.amount(&["amount"]).is_ok_and(|amount| amount < Decimal::ZERO)).ok_or_else(missing_leg)?;
+    let received = legs.iter().find(|leg| leg.amount(&["amount"]).is_ok_and(|amount| amount > Decimal::ZERO)).ok_or_else(missing_leg)?;
+
+    let (given_asset, received_asset) = (normalize_asset(&given.get(&["asset"])), normalize_asset(&received.get(&["asset"])));
+    let (given_amount, received_amount) = (given.amount(&["amount"])?.abs(), received.amount(&["amount"])?);
+
+    // The fee is charged on one of the legs, in that leg's asset
+    let (fee_amount, fee_asset) = match given.amount(&["fee"])? {
+        fee if fee.is_zero() => (received.amount(&["fee"])?, received_asset.to_owned()),
+        fee => (fee, given_asset.to_owned()),
+    };
+
+    let (txn_type, base_asset, base_asset_amount, quote_asset, quote_asset_amount) = match is_fiat(&received_asset) && !is_fiat(&given_asset) {
+        true => (TxnType::Sale, given_asset, given_amount, received_asset, received_amount),
+        false => (TxnType::Buy, received_asset, received_amount, given_asset, given_amount),
+    };
+    let record = CsvRecord {
+        user_txn_id: Some(refid.to_string()),
+        timestamp: given.get(&["time"]),
+        txn_type: given.get(&["type"]),
+        base_asset,
+        base_asset_amount: base_asset_amount.to_string(),
+        quote_asset,
+        quote_asset_amount: quote_asset_amount.to_string(),
+        fee_amount: Some(fee_amount.to_string()),
+        fee_asset: Some(fee_asset),
+        venue: Some(VENUE.to_string()),
+        ..Default::default()
+    };
+    Trade::with_txn_type(record, Some(txn_type), config)
+}
+
+
+/// A ledger row that isn't part of a trade: transfers, staking rewards and wallet moves
+fn ledger_row(row: &ExportRow, config: &Config, price_history: Option<&PriceHistory>) -> Result<Trade, FieldError> {
+    let raw_type = row.get(&["type"]);
+    let asset = normalize_asset(&row.get(&["asset"]));
+    let amount = row.amount(&["amount"])?;
+    let fee = row.amount(&["fee"])?;
+
+    let txn_type = match (raw_type.as_str(), row.get(&["subtype"]).as_str()) {
+        ("deposit" | "withdrawal", _) if is_fiat(&asset) => Some(TxnType::Other),
+        ("deposit", _) => Some(TxnType::Deposit),
+        ("withdrawal", _) => Some(TxnType::Withdrawal),
+        ("staking", _) | ("earn", "reward") if amount > Decimal::ZERO => Some(TxnType::Income(IncomeKind::Staking)),
+        ("staking" | "earn" | "transfer", _) => Some(TxnType::Other),
+        _ => None,
+    };
+
+    // A withdrawal sends its amount plus the network fee, which is what leaves the account
+    let base_asset_amount = match txn_type {
+        Some(TxnType::Withdrawal) => amount.abs() + fee,
+        _ => amount.abs(),
+    };
+    // Rewards take Kraken's USD value when the export has one, else their value in the price history
+    let timestamp = row.get(&["time"]);
+    let amount_usd = row.amount(&["amountusd"])?.abs();
+    let (quote_asset, quote_asset_amount) = match txn_type {
+        Some(TxnType::Income(_)) if config.price_source.currency == "USD" && !amount_usd.is_zero() => ("USD".to_string(), amount_usd),
+        Some(TxnType::Income(_)) => (config.price_source.currency.to_owned(), income_value(&asset, base_asset_amount, &timestamp, price_history)?),
+        _ => (asset.to_owned(), Decimal::ZERO),
+    };
+
+    let record = CsvRecord {
+        user_txn_id: Some(row.get(&["refid"])),
+        timestamp,
+        txn_type: raw_type,
+        base_asset: asset,
+        base_asset_amount: base_asset_amount.to_string(),
+        quote_asset,
+        quote_asset_amount: quote_asset_amount.to_string(),
+        venue: Some(VENUE.to_string()),
+        ..Default::default()
+    };
+    Trade::with_txn_type(record, txn_type, config)
+}
@@ -0,0 +1,132 @@
+//! Native importers for exchange exports, chosen with `[file_info] format`. Each reads the exchange's own
+//! layout and types its rows itself, so no `[csv_columns]` mapping or txn type lists are needed
+
+pub mod binance;
+pub mod coinbase;
+pub mod gemini;
+pub mod kraken;
+
+use csv::{ReaderBuilder, StringRecord};
+use rust_decimal::Decimal;
+use std::path::Path;
+
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::price_source::PriceHistory;
+use crate::funcs::trade::{parse_datetime_string, FieldError, ValueParseError};
+
+
+/// Quote assets recognised at the end of a pair with no separator, longest first so USDT wins over USD
+const QUOTE_ASSETS: [&str; 17] = [
+    "FDUSD", "USDT", "USDC", "BUSD", "TUSD", "USD", "EUR", "GBP", "CAD", "AUD", "JPY", "TRY", "DAI", "BTC", "ETH", "BNB", "XBT",
+];
+
+
+/// One row of an export, read by header name
+pub struct ExportRow<'a> {
+    headers: &'a StringRecord,
+    record: StringRecord,
+    pub line: u64,
+}
+
+impl ExportRow<'_> {
+    /// The first of `names` present in the headers, trimmed. Empty when none is
+    pub fn get(&self, names: &[&str]) -> String {
+        names.iter()
+            .find_map(|name| self.headers.iter().position(|header| header.trim() == *name))
+            .and_then(|i| self.record.get(i))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Amount in the first of `names`, see `parse_amount`. Empty cells are zero
+    pub fn amount(&self, names: &[&str]) -> Result<Decimal, FieldError> {
+        parse_amount(&self.get(names)).map_err(|e| FieldError::new(names[0], e))
+    }
+}
+
+
+/// Reads an export, skipping any preamble above the line containing the `header` column.
+/// Rows keep the file line they came from
+pub fn read_export(filepath: &Path, header: &str) -> Result<(StringRecord, Vec<(u64, StringRecord)>), CryptotaxError> {
+    let csv_error = |source: csv::Error| CryptotaxError::Csv { filepath: filepath.to_path_buf(), source };
+
+    let mut rdr = ReaderBuilder::new().has_headers(false).flexible(true).from_path(filepath).map_err(csv_error)?;
+    let mut headers: Option<StringRecord> = None;
+    let mut rows: Vec<(u64, StringRecord)> = vec![];
+
+    for result in rdr.records() {
+        let row = result.map_err(csv_error)?;
+        if headers.is_none() {
+            if row.iter().any(|cell| cell.trim() == header) {
+                headers = Some(row);
+            }
+            continue;
+        }
+        let line = row.position().map(|position| position.line()).unwrap_or_default();
+        rows.push((line, row));
+    }
+    Ok((headers.unwrap_or_default(), rows))
+}
+
+
+/// Pairs each row with the headers for reading by name
+pub fn export_rows<'a>(headers: &'a StringRecord, rows: Vec<(u64, StringRecord)>) -> impl Iterator<Item = ExportRow<'a>> {
+    rows.into_iter().map(move |(line, record)| ExportRow { headers, record, line })
+}
+
+
+/// Parses an amount as exchanges print it, eg "$1,000.50", "(0.5 BTC)" or "-€20". Parentheses are negative
+pub fn parse_amount(raw: &str) -> Result<Decimal, ValueParseError> {
+    let negative = raw.trim().starts_with('(') && raw.trim().ends_with(')');
+    let cleaned: String = raw.chars()
+        .filter(|c| c.is_ascii_digit() || matches!(c, '.' | '-'))
+        .collect();
+
+    if cleaned.is_empty() {
+        return Ok(Decimal::ZERO);
+    }
+    let amount = cleaned.parse::<Decimal>()
+        .map_err(|e| ValueParseError::DecimalParseError { value: raw.to_string(), source: e })?;
+    Ok(if negative { -amount } else { amount })
+}
+
+
+/// Splits an amount glued to its asset, eg "0.5BTC" into (0.5, "BTC"). Tickers can start with a digit, so a
+/// split leaving one of the expected `assets` wins, eg "10.51INCH" is 10.5 1INCH. Otherwise it splits at the first letter
+pub fn split_amount_asset(raw: &str, assets: &[&str]) -> Result<(Decimal, String), ValueParseError> {
+    let raw = raw.trim();
+    let first_letter = raw.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(raw.len());
+    let split = (0..=first_letter).rev()
+        .filter(|&i| raw.is_char_boundary(i))
+        .find(|&i| assets.iter().any(|asset| raw[i..].trim().eq_ignore_ascii_case(asset)))
+        .unwrap_or(first_letter);
+    Ok((parse_amount(&raw[..split])?, raw[split..].trim().to_string()))
+}
+
+
+/// Splits a trading pair into base and quote. Takes "BTC/USD", "BTC-USD" or "BTCUSD"
+pub fn split_pair(pair: &str) -> Option<(String, String)> {
+    let pair = pair.trim().to_uppercase();
+    if let Some((base, quote)) = pair.split_once(['/', '-', '_']) {
+        return Some((base.to_string(), quote.to_string()));
+    }
+    QUOTE_ASSETS.iter()
+        .find_map(|quote| pair.strip_suffix(quote).filter(|base| !base.is_empty()).map(|base| (base.to_string(), quote.to_string())))
+}
+
+
+/// Error for a row the exchange's layout doesn't explain
+pub fn format_error(column: &str, message: String) -> FieldError {
+    FieldError::new(column, ValueParseError::ExchangeFormatError(message))
+}
+
+
+/// Fair market value of a reward the export gives no value for, from the price history.
+/// Without a price the row is an error, rather than income booked at zero
+pub fn income_value(asset: &str, amount: Decimal, timestamp: &str, price_history: Option<&PriceHistory>) -> Result<Decimal, FieldError> {
+    let time = parse_datetime_string(timestamp).map_err(|e| FieldError::new("timestamp", e))?;
+    price_history
+        .and_then(|history| history.price_at(asset, time))
+        .map(|price| price * amount)
+        .ok_or_else(|| FieldError::new("price", ValueParseError::MissingPriceError { asset: asset.to_string(), time }))
+}
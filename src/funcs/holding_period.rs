@@ -0,0 +1,42 @@
+use chrono::{Months, NaiveDateTime};
+use serde::Serialize;
+use std::fmt;
+
+
+/// Short- vs long-term classification of a disposal, which drives the applicable tax rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum HoldingPeriod {
+    #[serde(rename = "Short-Term")]
+    ShortTerm,
+    #[serde(rename = "Long-Term")]
+    LongTerm,
+}
+
+impl fmt::Display for HoldingPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HoldingPeriod::ShortTerm => write!(f, "Short-Term"),
+            HoldingPeriod::LongTerm => write!(f, "Long-Term"),
+        }
+    }
+}
+
+impl HoldingPeriod {
+    /// Classifies a holding as long-term when the sale date falls after the anniversary
+    /// `long_term_months` after the acquisition date (US: held "more than one year").
+    ///
+    /// Only calendar dates are compared. An asset acquired on Feb 29 has its anniversary on Feb 28
+    /// of a non-leap year, so a sale on Mar 1 is long-term.
+    pub fn classify(acquired: NaiveDateTime, disposed: NaiveDateTime, long_term_months: u32) -> Self {
+        let anniversary = acquired
+            .date()
+            .checked_add_months(Months::new(long_term_months))
+            .unwrap_or(chrono::NaiveDate::MAX);
+
+        if disposed.date() > anniversary {
+            HoldingPeriod::LongTerm
+        } else {
+            HoldingPeriod::ShortTerm
+        }
+    }
+}
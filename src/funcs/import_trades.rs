@@ -1,48 +1,200 @@
 
 use csv::{ReaderBuilder, StringRecord};
 
-use std::collections::HashMap;
-use std::error::Error;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 
-use crate::funcs::config::Config;
-use crate::funcs::trade::Trade;
+use crate::funcs::config::{Config, FileFormat, ImportErrorPolicy};
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::exchanges;
+use crate::funcs::price_source::PriceHistory;
+use crate::funcs::starting_lots::{import_starting_lots, seed_lots};
+use crate::funcs::swaps::link_swaps;
+use crate::funcs::trade::{FieldError, Trade, ValueParseError};
 
 
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Deserialize, Debug, Default)]
 pub struct CsvRecord {
+    #[serde(default)]
+    pub user_txn_id: Option<String>,
     pub timestamp: String,
     pub txn_type: String,
     pub base_asset: String,
     pub base_asset_amount: String,
     pub quote_asset: String,
     pub quote_asset_amount: String,
+    #[serde(default)]
+    pub fee_amount: Option<String>,
+    #[serde(default)]
+    pub fee_asset: Option<String>,
+    #[serde(default)]
+    pub fee_value: Option<String>, // Value of the fee in the quote asset. Required when paid in a third asset
+    #[serde(default)]
+    pub venue: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    #[serde(default)]
+    pub tx_hash: Option<String>,
 }
 
 
-pub fn import_trades(config: &Config) -> Result<HashMap<String, Vec<Trade>>, Box<dyn Error>> {
+/// A trade parsed from a csv line, or why it couldn't be
+pub type ImportedRow = (u64, Result<Trade, FieldError>);
+
+/// Trades keyed by base asset. Ordered, so every run iterates the assets the same way
+pub type SortedTrades = BTreeMap<String, Vec<Trade>>;
+
+
+/// A csv row that could not be turned into a Trade. One line of the import-errors report
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportError {
+    pub filepath: PathBuf,
+    pub line: u64,
+    pub column: String,
+    pub message: String,
+}
+
+impl ImportError {
+    fn new(filepath: &PathBuf, line: u64, error: &FieldError) -> Self {
+        ImportError {
+            filepath: filepath.to_owned(),
+            line,
+            column: error.column.to_owned(),
+            message: error.source.to_string(),
+        }
+    }
+}
+
+
+/// Imports trades keyed by base asset. Rows that fail to parse are skipped when the config allows it
+pub fn import_trades(config: &Config) -> Result<SortedTrades, CryptotaxError> {
+    import_trades_with_errors(config).map(|(trades, _)| trades)
+}
+
+
+/// Imports trades keyed by base asset, along with the rows that failed to parse under `ImportErrorPolicy::Skip`.
+/// `Fail` stops at the first bad row, `Collect` parses every row and fails with all of them
+pub fn import_trades_with_errors(config: &Config) -> Result<(SortedTrades, Vec<ImportError>), CryptotaxError> {
+
+    // Crypto-to-crypto trades are valued from the price history, when one is configured
+    let price_history: Option<PriceHistory> = match config.price_source.filepath.as_os_str().is_empty() {
+        true => None,
+        false => Some(PriceHistory::from_file(&config.price_source.filepath)?),
+    };
+    let fx_rates: Option<PriceHistory> = match config.fx_rates.as_os_str().is_empty() {
+        true => None,
+        false => Some(PriceHistory::from_file(&config.fx_rates)?),
+    };
 
     // File import
-    let mut rdr = ReaderBuilder::new().has_headers(true).from_path(&config.filepath)?;
-    let updated_headers = replace_header_names(rdr.headers()?, &config.csv_columns);
-    rdr.set_headers(updated_headers.to_owned());
+    let rows: Vec<ImportedRow> = match config.file_format {
+        FileFormat::Generic => import_generic_rows(config)?,
+        FileFormat::Coinbase => exchanges::coinbase::import_rows(config)?,
+        FileFormat::Kraken => exchanges::kraken::import_rows(config, price_history.as_ref())?,
+        FileFormat::Binance => exchanges::binance::import_rows(config, price_history.as_ref())?,
+        FileFormat::Gemini => exchanges::gemini::import_rows(config, price_history.as_ref())?,
+    };
+
+    let mut row_trades: Vec<Trade> = vec![];
+    let mut import_errors: Vec<ImportError> = vec![];
+
+    for (line, result) in rows {
+        match result {
+            Ok(trade) => row_trades.push(Trade { source_row: line, ..trade }),
+            Err(source) if config.import_errors == ImportErrorPolicy::Fail => {
+                return Err(CryptotaxError::Record { filepath: config.filepath.to_owned(), line, source });
+            }
+            Err(source) => import_errors.push(ImportError::new(&config.filepath, line, &source)),
+        };
+    }
+
+    // Swap legs are linked while they are still the rows as given
+    link_swaps(&mut row_trades, config, price_history.as_ref())?;
 
+    let mut sorted_trades: SortedTrades = BTreeMap::new(); // Where trades are stored
 
-    let mut sorted_trades: HashMap<String, Vec<Trade>> = HashMap::new(); // Where trades are stored
-    
-    for result in rdr.deserialize() {
-        
-        let trade: Trade = Trade::new(result?, config)?;
-        let asset_name = trade.base_asset.to_owned();
+    for trade in row_trades {
+        let source_row = trade.source_row;
+        let legs: Vec<Trade> = match &price_history {
+            Some(history) => history.value_legs(trade, &config.price_source.currency)?,
+            None => vec![trade],
+        };
 
-        let trades = sorted_trades.entry(asset_name).or_default();
-        trades.push(trade);
+        for mut trade in legs.into_iter().map(|leg| Trade { source_row, ..leg }) {
+            if let Some(rates) = &fx_rates {
+                trade = rates.convert(trade, &config.reporting_currency)?;
+            }
+            let asset_name = trade.base_asset.to_owned();
+
+            if let Some(fee_disposal) = trade.fee_disposal() {
+                sorted_trades.entry(fee_disposal.base_asset.to_owned()).or_default().push(fee_disposal);
+            }
+
+            let trades = sorted_trades.entry(asset_name).or_default();
+            trades.push(trade);
+        }
+    }
+
+    if config.import_errors == ImportErrorPolicy::Collect && !import_errors.is_empty() {
+        return Err(CryptotaxError::InvalidRows(import_errors));
+    }
+
+    // Carried-in lots enter the buy lists ahead of the trades, without needing BUY rows
+    if !config.opening_lots.as_os_str().is_empty() {
+        seed_lots(&mut sorted_trades, &import_starting_lots(&config.opening_lots)?, &config.reporting_currency);
     }
 
-    Ok(sorted_trades)
+    Ok((sorted_trades, import_errors))
 }
 
 
 
+/// Reads the trades csv through the `[csv_columns]` mapping, one trade per line
+fn import_generic_rows(config: &Config) -> Result<Vec<ImportedRow>, CryptotaxError> {
+    let csv_error = |source: csv::Error| CryptotaxError::Csv { filepath: config.filepath.to_owned(), source };
+
+    // Flexible, so a malformed row is reported under the error policy rather than stopping the import
+    let mut rdr = ReaderBuilder::new().has_headers(true).flexible(true).from_path(&config.filepath).map_err(csv_error)?;
+    let updated_headers = replace_header_names(rdr.headers().map_err(csv_error)?, &config.csv_columns);
+
+    let mut rows: Vec<ImportedRow> = vec![];
+    for result in rdr.records() {
+        let row = match result {
+            Ok(row) => row,
+            Err(e) if e.is_io_error() => return Err(csv_error(e)),
+            Err(e) => {
+                let line = e.position().map(|position| position.line()).unwrap_or_default();
+                rows.push((line, Err(record_error(&e, &updated_headers))));
+                continue;
+            }
+        };
+        let line = row.position().map(|position| position.line()).unwrap_or_default();
+
+        if row.len() != updated_headers.len() {
+            let source = ValueParseError::FieldCountError { expected: updated_headers.len(), found: row.len() };
+            rows.push((line, Err(FieldError::new("row", source))));
+            continue;
+        }
+        let result = row.deserialize::<CsvRecord>(Some(&updated_headers))
+            .map_err(|e| record_error(&e, &updated_headers))
+            .and_then(|record| Trade::new(record, config));
+        rows.push((line, result));
+    }
+    Ok(rows)
+}
+
+
+/// A row the csv reader couldn't read, tagged with the column at fault when it is known
+fn record_error(error: &csv::Error, headers: &StringRecord) -> FieldError {
+    let column = match error.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.field().and_then(|i| headers.get(i as usize)),
+        _ => None,
+    };
+    FieldError::new(column.unwrap_or("row"), ValueParseError::RecordError(error.to_string()))
+}
+
+
 /// Updates the headers in a `StringRecord` with the corresponding values from a HashMap, ignore others
 fn replace_header_names(headers: &StringRecord, column_map: &HashMap<String, String>) -> StringRecord {
     let mut updated_headers: Vec<String> = Vec::new();
@@ -0,0 +1,87 @@
+//! Ordinary income (staking, mining, airdrops, interest, referrals), valued at fair market value on receipt.
+//! The lots it creates are matched like buys; this is the separate income side of the report
+
+use chrono::{Datelike, NaiveDateTime};
+use polars::prelude::*;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::funcs::config::Rounding;
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::import_trades::SortedTrades;
+use crate::funcs::process_trades::{decimal_series, serialize_datetime};
+use crate::funcs::txn_type::{IncomeKind, TxnType};
+
+
+/// A single receipt of income
+#[derive(Debug, Clone, Serialize)]
+pub struct IncomeEvent {
+    pub name: String,
+    #[serde(serialize_with = "serialize_datetime")]
+    pub date: NaiveDateTime,
+    pub kind: IncomeKind,
+    pub amount: Decimal,
+    pub value: Decimal, // Fair market value on receipt, which is also the lot's cost basis
+    pub currency: String,
+    pub txn_id: Option<String>,
+    pub year: i32,
+}
+
+impl IncomeEvent {
+    /// Copy of the income event with its value rounded for reporting
+    pub fn rounded(&self, rounding: &Rounding) -> Self {
+        Self {
+            value: rounding.apply(self.value),
+            ..self.clone()
+        }
+    }
+}
+
+
+/// All income events across assets, by date then asset
+pub fn get_income_events(all_trades: &SortedTrades) -> Vec<IncomeEvent> {
+    let mut income_events: Vec<IncomeEvent> = all_trades
+        .values()
+        .flatten()
+        .filter_map(|trade| match trade.txn_type {
+            TxnType::Income(kind) => Some(IncomeEvent {
+                name: trade.base_asset.to_owned(),
+                date: trade.trade_time,
+                kind,
+                amount: trade.base_asset_amount,
+                value: trade.quote_asset_amount,
+                currency: trade.quote_asset.to_owned(),
+                txn_id: trade.txn_id.to_owned(),
+                year: trade.trade_time.year(),
+            }),
+            _ => None,
+        })
+        .collect();
+
+    income_events.sort_by(|a, b| (a.date, &a.name).cmp(&(b.date, &b.name)));
+    income_events
+}
+
+
+/// Summarises income value per kind (rows) and year (columns). Totals are summed exactly, then rounded
+pub fn get_income_summary(income_events: &[IncomeEvent], rounding: &Rounding) -> Result<DataFrame, CryptotaxError> {
+    let kinds: BTreeSet<IncomeKind> = income_events.iter().map(|event| event.kind).collect();
+
+    // <Year, <Kind, Value>>, with every kind present in every year
+    let mut map: BTreeMap<i32, BTreeMap<IncomeKind, Decimal>> = BTreeMap::new();
+
+    for event in income_events {
+        let year = map.entry(event.year).or_insert_with(|| kinds.iter().map(|kind| (*kind, Decimal::ZERO)).collect());
+        *year.entry(event.kind).or_default() += event.value;
+    }
+
+    let kind_series = Series::new("Income Type", kinds.iter().map(|kind| kind.to_string()).collect::<Vec<String>>());
+    let mut df = DataFrame::new(vec![kind_series])?;
+
+    for (year, totals) in map {
+        df.with_column(decimal_series(&year.to_string(), totals.values().cloned(), rounding))?;
+    }
+
+    Ok(df)
+}
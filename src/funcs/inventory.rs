@@ -0,0 +1,98 @@
+//! Sales that exceed the holdings available to match them, and the configured policy for the unmatched amount
+
+use chrono::NaiveDateTime;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::funcs::config::{Config, ShortfallPolicy};
+use crate::funcs::process_trades::{MatchRule, SaleEvent, DUST_THRESHOLD};
+use crate::funcs::trade::Trade;
+
+
+#[derive(thiserror::Error, Debug)]
+pub enum InventoryError {
+    #[error("ERROR: Sale of {asset} on {sale_date} exceeds available holdings by {unmatched_amount}")]
+    InsufficientInventory {
+        asset: String,
+        sale_date: NaiveDateTime,
+        unmatched_amount: Decimal,
+    },
+}
+
+
+/// Diagnostic for a sale that could not be fully matched against the available holdings
+#[derive(Debug, Clone, Serialize)]
+pub struct Shortfall {
+    pub asset: String,
+    pub sale_date: NaiveDateTime,
+    pub txn_id: Option<String>,
+    pub unmatched_amount: Decimal,
+}
+
+
+/// The configured opening-balance lot for an asset, if any. Only used under the `opening_balance` policy,
+/// so it doesn't show up among the open lots otherwise
+pub fn opening_balance_lot(config: &Config, asset: &str, quote_asset: &str) -> Option<Trade> {
+    if config.inventory.policy != ShortfallPolicy::OpeningBalance {
+        return None;
+    }
+    config.inventory.opening_balances.get(asset).map(|opening| {
+        Trade::synthetic_lot(asset, quote_asset, opening.amount, opening.cost_basis, opening.acquired, Some("opening-balance".to_string()))
+    })
+}
+
+
+/// Records any quantity left on a sale after matching, then applies the shortfall policy to it
+pub fn resolve_shortfall(
+    sale: &mut Trade,
+    config: &Config,
+    opening_lot: Option<&mut Trade>,
+    sale_events: &mut Vec<SaleEvent>,
+    shortfalls: &mut Vec<Shortfall>,
+) -> Result<(), InventoryError> {
+    let long_term_months = config.holding_period.long_term_months;
+
+    if sale.remaining < DUST_THRESHOLD {
+        return Ok(());
+    }
+
+    shortfalls.push(Shortfall {
+        asset: sale.base_asset.to_owned(),
+        sale_date: sale.trade_time,
+        txn_id: sale.txn_id.to_owned(),
+        unmatched_amount: sale.remaining,
+    });
+
+    match config.inventory.policy {
+        ShortfallPolicy::Fail => {}
+        ShortfallPolicy::ZeroBasis => {
+            let lot = Trade::synthetic_lot(&sale.base_asset, &sale.quote_asset, sale.remaining, Decimal::ZERO, sale.trade_time, None);
+            let mut event = SaleEvent::new(&lot, sale, sale.remaining, long_term_months);
+            event.match_rule = MatchRule::ZeroBasis;
+
+            sale_events.push(event);
+            sale.remaining = Decimal::ZERO;
+        }
+        ShortfallPolicy::OpeningBalance => {
+            if let Some(lot) = opening_lot.filter(|lot| lot.unix_time <= sale.unix_time && lot.remaining >= DUST_THRESHOLD) {
+                let clip_size = lot.remaining.min(sale.remaining);
+                let mut event = SaleEvent::new(lot, sale, clip_size, long_term_months);
+                event.match_rule = MatchRule::OpeningBalance;
+
+                sale_events.push(event);
+                lot.remaining -= clip_size;
+                sale.remaining -= clip_size;
+            }
+        }
+    }
+
+    if sale.remaining >= DUST_THRESHOLD {
+        return Err(InventoryError::InsufficientInventory {
+            asset: sale.base_asset.to_owned(),
+            sale_date: sale.trade_time,
+            unmatched_amount: sale.remaining,
+        });
+    }
+
+    Ok(())
+}
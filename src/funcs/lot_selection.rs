@@ -0,0 +1,64 @@
+//! Per-sale lot ordering for the tax-optimising accounting types, which depend on the sale itself
+
+use rust_decimal::Decimal;
+use std::cmp::Reverse;
+
+use crate::funcs::config::{AccountingType, Config};
+use crate::funcs::holding_period::HoldingPeriod;
+use crate::funcs::process_trades::DUST_THRESHOLD;
+use crate::funcs::trade::Trade;
+
+
+/// Returns the indices of the buy lots available to a sale, in the order they should be consumed.
+/// `buys` is expected to already be sorted by `build_buy_list`, which is kept as the tie-break
+pub fn order_lots(buys: &[Trade], sale: &Trade, acct_type: &AccountingType, config: &Config) -> Vec<usize> {
+
+    // Only lots acquired before the sale in the total order, which settles ties on a shared timestamp
+    let mut eligible: Vec<usize> = (0..buys.len())
+        .filter(|&i| buys[i].cmp_chronological(sale, config.tie_breaker).is_le() && buys[i].remaining >= DUST_THRESHOLD)
+        .collect();
+
+    let sale_unit_proceeds = unit_proceeds(sale);
+    let long_term_months = config.holding_period.long_term_months;
+
+    match acct_type {
+        AccountingType::HTFO => eligible.sort_by_key(|&i| {
+            let buy = &buys[i];
+            let gain = sale_unit_proceeds - unit_cost(buy);
+            let holding_period = HoldingPeriod::classify(buy.trade_time, sale.trade_time, long_term_months);
+
+            // Short-term losses, long-term losses, long-term gains, short-term gains. Highest cost first within each
+            let priority = match (gain.is_sign_negative(), holding_period) {
+                (true, HoldingPeriod::ShortTerm) => 0,
+                (true, HoldingPeriod::LongTerm) => 1,
+                (false, HoldingPeriod::LongTerm) => 2,
+                (false, HoldingPeriod::ShortTerm) => 3,
+            };
+            (priority, Reverse(unit_cost(buy)))
+        }),
+        AccountingType::MinTax => eligible.sort_by_key(|&i| {
+            let buy = &buys[i];
+            let gain = sale_unit_proceeds - unit_cost(buy);
+            let rate = match HoldingPeriod::classify(buy.trade_time, sale.trade_time, long_term_months) {
+                HoldingPeriod::ShortTerm => config.tax_rates.short_term,
+                HoldingPeriod::LongTerm => config.tax_rates.long_term,
+            };
+            gain * rate // Estimated tax per unit, lowest (largest saving) first
+        }),
+        _ => {}
+    }
+
+    eligible
+}
+
+
+/// Cost per unit of a buy lot, including purchase fees
+fn unit_cost(buy: &Trade) -> Decimal {
+    (buy.quote_asset_amount + buy.fee) / buy.base_asset_amount
+}
+
+
+/// Proceeds per unit of a sale, net of sale fees
+fn unit_proceeds(sale: &Trade) -> Decimal {
+    (sale.quote_asset_amount - sale.fee) / sale.base_asset_amount
+}
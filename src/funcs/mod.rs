@@ -1,5 +1,22 @@
 pub mod config;
+pub mod csv_util;
+pub mod engine;
+pub mod exchanges;
+pub mod error;
+pub mod holding_period;
 pub mod process_trades;
+pub mod safe_harbor;
+pub mod specific_id;
+pub mod starting_lots;
+pub mod swaps;
 pub mod import_trades;
+pub mod income;
+pub mod inventory;
+pub mod lot_selection;
+pub mod open_lots;
+pub mod price_source;
 pub mod txn_type;
-pub mod trade;
\ No newline at end of file
+pub mod trade;
+pub mod transfers;
+pub mod uk_pooling;
+pub mod wallets;
\ No newline at end of file
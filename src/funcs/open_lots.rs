@@ -0,0 +1,64 @@
+//! Open-lots report: the unrealised inventory left after matching, lot by lot. It is the basis carried
+//! into the next year's filing
+
+use chrono::NaiveDateTime;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::funcs::config::Rounding;
+use crate::funcs::holding_period::HoldingPeriod;
+use crate::funcs::process_trades::{serialize_datetime, OpenLots, DUST_THRESHOLD};
+
+
+/// A lot with a remaining balance
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OpenLot {
+    pub name: String,
+    #[serde(serialize_with = "serialize_datetime")]
+    pub acquired: NaiveDateTime,
+    pub txn_id: Option<String>,
+    pub venue: Option<String>,
+    pub remaining: Decimal,
+    pub unit_cost: Decimal, // Including purchase fees
+    pub cost_basis: Decimal, // Of the remaining amount
+    pub currency: String,
+    pub holding_period: HoldingPeriod, // As of the report date
+}
+
+impl OpenLot {
+    /// Copy of the open lot with its cost basis rounded for reporting
+    pub fn rounded(&self, rounding: &Rounding) -> Self {
+        Self {
+            cost_basis: rounding.apply(self.cost_basis),
+            ..self.clone()
+        }
+    }
+}
+
+
+/// Lists the open lots by asset then acquisition date, classifying each holding period as if sold on `as_of`
+pub fn get_open_lot_report(open_lots: &OpenLots, as_of: NaiveDateTime, long_term_months: u32) -> Vec<OpenLot> {
+
+    let mut report: Vec<OpenLot> = open_lots
+        .iter()
+        .flat_map(|(asset, lots)| lots.iter().map(move |lot| (asset, lot)))
+        .filter(|(_, lot)| lot.remaining >= DUST_THRESHOLD)
+        .map(|(asset, lot)| {
+            let cost_basis = lot.pro_rata(lot.quote_asset_amount + lot.fee, lot.remaining);
+            OpenLot {
+                name: asset.to_owned(),
+                acquired: lot.trade_time,
+                txn_id: lot.txn_id.to_owned(),
+                venue: lot.venue.to_owned(),
+                remaining: lot.remaining,
+                unit_cost: cost_basis / lot.remaining,
+                cost_basis,
+                currency: lot.quote_asset.to_owned(),
+                holding_period: HoldingPeriod::classify(lot.trade_time, as_of, long_term_months),
+            }
+        })
+        .collect();
+
+    report.sort_by(|a, b| (&a.name, a.acquired, &a.txn_id, &a.venue).cmp(&(&b.name, b.acquired, &b.txn_id, &b.venue)));
+    report
+}
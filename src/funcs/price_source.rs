@@ -0,0 +1,216 @@
+//! Historical prices in a single currency, used to value crypto-to-crypto trades at fair market value.
+//! FX rate tables use the same format, with the fiat currency as the asset and the rate in the reporting currency
+
+use chrono::NaiveDateTime;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::funcs::csv_util::read_records;
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::trade::{parse_datetime_string, FieldError, Trade, ValueParseError};
+use crate::funcs::txn_type::TxnType;
+
+
+#[derive(thiserror::Error, Debug)]
+pub enum PriceError {
+    #[error("ERROR: No price for {asset} at or before {time}")]
+    MissingPrice { asset: String, time: NaiveDateTime },
+    #[error("ERROR: Unsupported price file {}. Use csv, or parquet with the `parquet` feature", .0.display())]
+    UnsupportedFormat(PathBuf),
+    #[cfg(feature = "parquet")]
+    #[error("ERROR: Could not read {}.\nMSG: {source}", filepath.display())]
+    Parquet {
+        filepath: PathBuf,
+        #[source]
+        source: polars::prelude::PolarsError,
+    },
+}
+
+
+/// One row of the price file
+#[derive(serde::Deserialize, Debug)]
+struct PriceRecord {
+    asset: String,
+    timestamp: String,
+    price: String,
+}
+
+
+/// Fiat currencies. A trade quoted in one of these can only be reported in another at an FX rate
+const FIAT: [&str; 20] = [
+    "USD", "EUR", "GBP", "CAD", "AUD", "JPY", "CHF", "NZD", "SGD", "HKD", "SEK", "NOK", "DKK", "PLN", "KRW", "INR", "BRL", "MXN", "ZAR", "TRY",
+];
+
+pub fn is_fiat(asset: &str) -> bool {
+    FIAT.contains(&asset)
+}
+
+
+/// Price per unit of each asset over time
+#[derive(Debug, Clone, Default)]
+pub struct PriceHistory {
+    prices: HashMap<String, BTreeMap<NaiveDateTime, Decimal>>,
+}
+
+impl PriceHistory {
+    /// Loads an `asset,timestamp,price` file. Csv always, parquet with the `parquet` feature
+    pub fn from_file(filepath: &Path) -> Result<Self, CryptotaxError> {
+        let extension = filepath.extension().and_then(|extension| extension.to_str()).map(str::to_lowercase);
+
+        match extension.as_deref() {
+            Some("csv") => Self::from_csv(filepath),
+            #[cfg(feature = "parquet")]
+            Some("parquet") => Self::from_parquet(filepath),
+            _ => Err(PriceError::UnsupportedFormat(filepath.to_path_buf()).into()),
+        }
+    }
+
+    fn from_csv(filepath: &Path) -> Result<Self, CryptotaxError> {
+        let mut history = PriceHistory::default();
+        read_records(filepath, |record: PriceRecord| history.insert_record(record))?;
+        Ok(history)
+    }
+
+    #[cfg(feature = "parquet")]
+    fn from_parquet(filepath: &Path) -> Result<Self, CryptotaxError> {
+        use polars::prelude::*;
+
+        let parquet_error = |source: PolarsError| PriceError::Parquet { filepath: filepath.to_path_buf(), source };
+        let file = std::fs::File::open(filepath).map_err(|e| parquet_error(e.into()))?;
+        let df = ParquetReader::new(file).finish().map_err(parquet_error)?;
+
+        // Read every column as text so it goes through the same parsing as csv
+        let column = |name: &str| df.column(name).and_then(|series| series.cast(&DataType::String)).map_err(parquet_error);
+        let (assets, timestamps, prices) = (column("asset")?, column("timestamp")?, column("price")?);
+
+        let mut history = PriceHistory::default();
+        let rows = assets.str().map_err(parquet_error)?.into_iter()
+            .zip(timestamps.str().map_err(parquet_error)?)
+            .zip(prices.str().map_err(parquet_error)?);
+
+        for (index, ((asset, timestamp), price)) in rows.enumerate() {
+            let record = PriceRecord {
+                asset: asset.unwrap_or_default().to_string(),
+                timestamp: timestamp.unwrap_or_default().to_string(),
+                price: price.unwrap_or_default().to_string(),
+            };
+            history.insert_record(record).map_err(|source| CryptotaxError::Record { filepath: filepath.to_path_buf(), line: index as u64 + 2, source })?;
+        }
+        Ok(history)
+    }
+
+    fn insert_record(&mut self, record: PriceRecord) -> Result<(), FieldError> {
+        let time = parse_datetime_string(&record.timestamp).map_err(|e| FieldError::new("timestamp", e))?;
+        let price: Decimal = record.price.parse::<Decimal>()
+            .map_err(|e| FieldError::new("price", ValueParseError::DecimalParseError { value: record.price.clone(), source: e }))?;
+
+        self.insert(&record.asset, time, price);
+        Ok(())
+    }
+
+    pub fn insert(&mut self, asset: &str, time: NaiveDateTime, price: Decimal) {
+        self.prices.entry(asset.to_string()).or_default().insert(time, price);
+    }
+
+    pub fn has_asset(&self, asset: &str) -> bool {
+        self.prices.contains_key(asset)
+    }
+
+    /// Most recent price of the asset at or before `time`
+    pub fn price_at(&self, asset: &str, time: NaiveDateTime) -> Option<Decimal> {
+        self.prices.get(asset)?.range(..=time).next_back().map(|(_, price)| *price)
+    }
+
+    /// Splits a crypto-to-crypto trade into a disposal of the asset given and an acquisition of the asset received,
+    /// each valued at its own fair market value in `currency`. Trades quoted in fiat are returned as is, for the FX
+    /// conversion. A crypto quote asset with no prices at all is an error rather than being booked as `currency`
+    pub fn value_legs(&self, trade: Trade, currency: &str) -> Result<Vec<Trade>, PriceError> {
+        if let TxnType::Income(_) | TxnType::Withdrawal = trade.txn_type {
+            return Ok(vec![self.value_at_market(trade, currency)]);
+        }
+        if trade.txn_type == TxnType::Other || trade.quote_asset == currency || is_fiat(&trade.quote_asset) {
+            return Ok(vec![trade]);
+        }
+        if !self.has_asset(&trade.quote_asset) {
+            return Err(PriceError::MissingPrice { asset: trade.quote_asset.to_owned(), time: trade.trade_time });
+        }
+
+        let value = |asset: &str, amount: Decimal| self.price_at(asset, trade.trade_time).map(|price| price * amount);
+
+        // A leg without a price takes the value of the other leg
+        let (base_value, quote_value) = match (value(&trade.base_asset, trade.base_asset_amount), value(&trade.quote_asset, trade.quote_asset_amount)) {
+            (Some(base_value), Some(quote_value)) => (base_value, quote_value),
+            (Some(base_value), None) => (base_value, base_value),
+            (None, Some(quote_value)) => (quote_value, quote_value),
+            (None, None) => return Err(PriceError::MissingPrice { asset: trade.base_asset.to_owned(), time: trade.trade_time }),
+        };
+
+        // The fee is valued in the quote asset, so convert it at the quote leg's value
+        let fee = (trade.fee * quote_value).checked_div(trade.quote_asset_amount).unwrap_or_default();
+
+        let mut counter_leg = Trade::synthetic_lot(
+            &trade.quote_asset,
+            currency,
+            trade.quote_asset_amount,
+            quote_value,
+            trade.trade_time,
+            trade.txn_id.to_owned(),
+        );
+        counter_leg.txn_type = match trade.txn_type {
+            TxnType::Buy => TxnType::Sale,
+            _ => TxnType::Buy,
+        };
+        // Both legs happened on the trade's venue, in the trade's row
+        counter_leg.venue = trade.venue.to_owned();
+        counter_leg.tx_hash = trade.tx_hash.to_owned();
+        counter_leg.source_row = trade.source_row;
+
+        let mut leg = trade;
+        leg.quote_asset = currency.to_string();
+        leg.quote_asset_amount = base_value;
+        leg.price = base_value / leg.base_asset_amount;
+        leg.fee = fee;
+        leg.fx_currency = currency.to_string();
+
+        Ok(vec![leg, counter_leg])
+    }
+
+    /// Values income on receipt, and withdrawals for any network fee they pay, at the asset's price.
+    /// Without a price the value given in the csv is kept
+    fn value_at_market(&self, mut trade: Trade, currency: &str) -> Trade {
+        if let Some(price) = self.price_at(&trade.base_asset, trade.trade_time) {
+            trade.quote_asset = currency.to_string();
+            trade.quote_asset_amount = price * trade.base_asset_amount;
+            trade.price = price;
+            trade.fx_currency = currency.to_string();
+        }
+        trade
+    }
+
+    /// Converts a trade quoted in another fiat currency to `currency` at the rate on the trade date.
+    /// The original currency and rate are kept on the trade for audit. Trades quoted in a crypto asset without rates are
+    /// returned as is, but a fiat currency without a rate is an error rather than being booked 1:1
+    pub fn convert(&self, mut trade: Trade, currency: &str) -> Result<Trade, PriceError> {
+        if trade.quote_asset == currency || !(self.has_asset(&trade.quote_asset) || is_fiat(&trade.quote_asset)) {
+            return Ok(trade);
+        }
+
+        let rate = self.price_at(&trade.quote_asset, trade.trade_time)
+            .ok_or_else(|| PriceError::MissingPrice { asset: trade.quote_asset.to_owned(), time: trade.trade_time })?;
+
+        trade.quote_asset_amount *= rate;
+        trade.price *= rate;
+        trade.fee *= rate;
+
+        // A fee paid in the quote currency is converted with it, rather than becoming a disposal of that currency
+        if trade.fee_asset == trade.quote_asset {
+            trade.fee_asset = currency.to_string();
+            trade.fee_asset_amount = trade.fee;
+        }
+
+        trade.fx_currency = std::mem::replace(&mut trade.quote_asset, currency.to_string());
+        trade.fx_rate = rate;
+        Ok(trade)
+    }
+}
@@ -1,50 +1,125 @@
 use chrono::{Datelike, NaiveDateTime};
 use cli_table::Table;
 use itertools::Itertools;
-use rust_decimal::{prelude::ToPrimitive, Decimal};
-use std::cmp::Reverse;
+use rust_decimal::Decimal;
 use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::{cmp, collections::HashMap};
+use crate::funcs::import_trades::SortedTrades;
 use serde::{Serialize, Serializer};
 
 
-use crate::funcs::config::{AccountingType, Config};
+use crate::funcs::config::{AccountingType, Config, Rounding, SafeHarborConfig, TieBreaker};
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::holding_period::HoldingPeriod;
+use crate::funcs::lot_selection::order_lots;
 use crate::funcs::trade::Trade;
 use crate::funcs::txn_type::TxnType;
+use crate::funcs::inventory::{opening_balance_lot, resolve_shortfall, Shortfall};
+use crate::funcs::specific_id::{apply_assignments, import_lot_assignments, LotAssignment};
+use crate::funcs::starting_lots::{import_starting_lots, seed_lots};
+use crate::funcs::transfers::{match_transfers, TransferReport};
+use crate::funcs::uk_pooling::{self, PoolSnapshot};
+use crate::funcs::wallets;
 
 use polars::prelude::*;
 
 
+/// Amounts below this are treated as zero. Accounts for de minimis reporting errors
+pub(crate) const DUST_THRESHOLD: Decimal = Decimal::from_parts(1, 0, 0, false, 5);
+
+
+/// Asset name -> buy lots with a remaining balance
+pub type OpenLots = BTreeMap<String, Vec<Trade>>;
+
+/// Asset name -> average cost per unit of the remaining balance
+pub type CostBases = BTreeMap<String, Option<Decimal>>;
+
+
 /// SaleEvent holds individual sale events
 #[derive(Debug, Table, Clone, Serialize)]
 pub struct SaleEvent {
-    name: String,
+    pub name: String,
     #[serde(serialize_with = "serialize_datetime")]
-    buy_date: NaiveDateTime,
-    buy_date_unix: i64,
+    pub buy_date: NaiveDateTime,
+    pub buy_date_unix: i64,
     #[serde(serialize_with = "serialize_datetime")]
-    sale_date: NaiveDateTime,
-    sale_date_unix: i64,
-    purchase_price: f32,
-    sale_price: f32,
-    amount: Decimal,
-    pub gain_loss: f32,
+    pub sale_date: NaiveDateTime,
+    pub sale_date_unix: i64,
+    pub purchase_price: Decimal,
+    pub sale_price: Decimal,
+    pub buy_currency: String, // Currency the buy was quoted in, before conversion to the reporting currency
+    pub buy_fx_rate: Decimal,
+    pub sale_currency: String,
+    pub sale_fx_rate: Decimal,
+    pub venue: String, // Venue of the sale. Empty when unknown
+    pub buy_txn_id: String, // Source rows of the matched buy and the sale. Empty when unknown, or when the buy is a pool of several
+    pub sale_txn_id: String,
+    pub amount: Decimal,
+    pub proceeds: Decimal, // Sale value of the clipped amount, net of sale fees
+    pub cost_basis: Decimal, // Purchase value of the clipped amount, including purchase fees
+    pub fees: Decimal, // Purchase and sale fees allocated to the clipped amount
+    pub gain_loss: Decimal,
+    pub holding_period: HoldingPeriod,
+    pub match_rule: MatchRule,
     pub sell_year: i32,
 }
 
 
+/// How a sale was matched to its acquisition cost
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MatchRule {
+    Lot, // An individual buy lot, picked by the accounting type order
+    Pool, // Average cost of a pooled holding
+    #[serde(rename = "Same Day")]
+    SameDay, // UK: acquisition on the same day as the disposal
+    #[serde(rename = "Bed and Breakfast")]
+    BedAndBreakfast, // UK: acquisition in the 30 days after the disposal
+    #[serde(rename = "Section 104")]
+    Section104, // UK: Section 104 pool
+    #[serde(rename = "Specific ID")]
+    SpecificId, // Lot assigned by the user in the lot-assignment file
+    #[serde(rename = "Zero Basis")]
+    ZeroBasis, // Sale exceeded holdings, unmatched amount treated as zero cost
+    #[serde(rename = "Opening Balance")]
+    OpeningBalance, // Sale exceeded holdings, unmatched amount taken from the opening-balance lot
+}
+
+impl std::fmt::Display for MatchRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatchRule::Lot => write!(f, "Lot"),
+            MatchRule::Pool => write!(f, "Pool"),
+            MatchRule::SameDay => write!(f, "Same Day"),
+            MatchRule::BedAndBreakfast => write!(f, "Bed and Breakfast"),
+            MatchRule::Section104 => write!(f, "Section 104"),
+            MatchRule::SpecificId => write!(f, "Specific ID"),
+            MatchRule::ZeroBasis => write!(f, "Zero Basis"),
+            MatchRule::OpeningBalance => write!(f, "Opening Balance"),
+        }
+    }
+}
+
+
 // SaleEvent Constructor
 impl SaleEvent {
-    fn new(buy: &Trade, sale: &Trade, amount: Decimal) -> Self {
+    pub(crate) fn new(buy: &Trade, sale: &Trade, amount: Decimal, long_term_months: u32) -> Self {
         let name: String = sale.base_asset.to_owned();
         let buy_date: NaiveDateTime = buy.trade_time.to_owned();
         let buy_date_unix: i64 = buy.unix_time.to_owned();
         let sale_date = sale.trade_time.to_owned();
         let sale_date_unix: i64 = sale.unix_time.to_owned();
-        let purchase_price: f32 = buy.price.to_owned();
-        let sale_price = sale.price.to_owned();
-        let gain_loss = (sale_price - purchase_price) * amount.to_f32().unwrap();
+        let purchase_price: Decimal = buy.price.to_owned();
+        let sale_price: Decimal = sale.price.to_owned();
+
+        // Pro-rate the source trade totals to the clipped amount
+        let buy_fee: Decimal = buy.pro_rata(buy.fee, amount);
+        let sale_fee: Decimal = sale.pro_rata(sale.fee, amount);
+        let cost_basis: Decimal = buy.pro_rata(buy.quote_asset_amount, amount) + buy_fee;
+        let proceeds: Decimal = sale.pro_rata(sale.quote_asset_amount, amount) - sale_fee;
+        let fees: Decimal = buy_fee + sale_fee;
+        let gain_loss: Decimal = proceeds - cost_basis;
+        let holding_period = HoldingPeriod::classify(buy_date, sale_date, long_term_months);
         let sell_year = sale.trade_time.year();
 
         Self {
@@ -55,11 +130,38 @@ impl SaleEvent {
             sale_date_unix,
             purchase_price,
             sale_price,
+            buy_currency: buy.fx_currency.to_owned(),
+            buy_fx_rate: buy.fx_rate,
+            sale_currency: sale.fx_currency.to_owned(),
+            sale_fx_rate: sale.fx_rate,
+            venue: sale.venue.to_owned().unwrap_or_default(),
+            buy_txn_id: buy.txn_id.to_owned().unwrap_or_default(),
+            sale_txn_id: sale.txn_id.to_owned().unwrap_or_default(),
             amount,
+            proceeds,
+            cost_basis,
+            fees,
             gain_loss,
+            holding_period,
+            match_rule: MatchRule::Lot,
             sell_year,
         }
     }
+
+    /// Copy of the sale event with monetary totals rounded for reporting.
+    /// Gain-loss is recomputed from the rounded proceeds and basis so each row ties out
+    pub fn rounded(&self, rounding: &Rounding) -> Self {
+        let proceeds = rounding.apply(self.proceeds);
+        let cost_basis = rounding.apply(self.cost_basis);
+
+        Self {
+            proceeds,
+            cost_basis,
+            fees: rounding.apply(self.fees),
+            gain_loss: proceeds - cost_basis,
+            ..self.clone()
+        }
+    }
 }
 
 
@@ -72,74 +174,254 @@ impl SaleEvent {
 ///
 /// # Returns
 ///
-/// A list of sale events for the asset.
-pub fn get_sale_events_and_cost_basis(all_trades: HashMap<String, Vec<Trade>>, config: &Config) -> (Vec<SaleEvent>, HashMap<String, f32>){
+/// A list of sale events for the asset, and the average cost basis of the remaining holdings per asset.
+pub fn get_sale_events_and_cost_basis(all_trades: SortedTrades, config: &Config) -> Result<(Vec<SaleEvent>, CostBases), CryptotaxError> {
+    let (sale_events, open_lots) = get_sale_events_and_open_lots(all_trades, config)?;
+
+    let cost_bases: CostBases = open_lots
+        .iter()
+        .map(|(asset, lots)| (asset.to_owned(), calc_cost_basis(lots)))
+        .collect();
+
+    Ok((sale_events, cost_bases))
+}
+
+
+/// Matches sales against buys and returns the sale events along with the unsold buy lots.
+///
+/// # Arguments
+///
+/// * `trades` - A hash map of trades, which are asset names & Asset structs containting trades
+/// * `config` - The configuration object from the config ini file.
+///
+/// # Returns
+///
+/// A list of sale events, and a map of asset name to the buy lots with a remaining balance.
+pub fn get_sale_events_and_open_lots(all_trades: SortedTrades, config: &Config) -> Result<(Vec<SaleEvent>, OpenLots), CryptotaxError> {
+    let processed = process_trades(all_trades, config)?;
+    Ok((processed.sale_events, processed.open_lots))
+}
+
+
+/// Everything produced by matching sales against buys
+#[derive(Debug, Clone, Default)]
+pub struct ProcessedTrades {
+    pub sale_events: Vec<SaleEvent>,
+    pub open_lots: OpenLots,
+    pub shortfalls: Vec<Shortfall>, // Sales that exceeded the available holdings
+    pub pool_snapshots: Vec<PoolSnapshot>, // Section 104 pool at each 5 April. Only for UK pooling
+    pub transfers: TransferReport,
+}
+
+
+/// Pairs the transfers between venues, then matches sales against buys for every asset, using the configured
+/// accounting type and shortfall policy
+pub fn process_trades(mut all_trades: SortedTrades, config: &Config) -> Result<ProcessedTrades, CryptotaxError> {
+    let transfers = match_transfers(&mut all_trades, config);
+
+    let mut processed = match &config.safe_harbor {
+        Some(safe_harbor) => process_with_safe_harbor(all_trades, config, safe_harbor)?,
+        None => match_trades(all_trades, config)?,
+    };
+    processed.transfers = transfers;
+    Ok(processed)
+}
+
+
+/// Matches sales against buys for every asset, on trades whose transfers are already paired
+pub(crate) fn match_trades(all_trades: SortedTrades, config: &Config) -> Result<ProcessedTrades, CryptotaxError> {
     let mut sale_events: Vec<SaleEvent> = vec![];
-    let mut cost_bases: HashMap<String, f32> = HashMap::new();
-    let dust_threshold = Decimal::from_f32_retain(0.00001).unwrap(); // Account for deiminumus reporting errors
+    let mut open_lots: OpenLots = BTreeMap::new();
+    let mut shortfalls: Vec<Shortfall> = vec![];
+    let mut pool_snapshots: Vec<PoolSnapshot> = vec![];
+
+    let assignments: HashMap<String, Vec<LotAssignment>> = match config.accounting_type {
+        AccountingType::SpecificId => import_lot_assignments(&config.specific_id.filepath)?,
+        _ => HashMap::new(),
+    };
+
+    for (asset, trades) in all_trades.iter().filter(|(_, trades)| !trades.is_empty()) {
+
+        let remaining_lots = match config.accounting_type {
+            AccountingType::ACB => match_average_cost(trades, config, &mut sale_events, &mut shortfalls)?,
+            AccountingType::UkPooling => {
+                let (pool, snapshots) = uk_pooling::match_uk_pooling(trades, config, &mut sale_events, &mut shortfalls)?;
+                pool_snapshots.extend(snapshots);
+                pool
+            }
+            AccountingType::SpecificId => match_lots(trades, config, &config.specific_id.default_method, &assignments, &mut sale_events, &mut shortfalls)?,
+            _ => match_lots(trades, config, &config.accounting_type, &assignments, &mut sale_events, &mut shortfalls)?,
+        };
+
+        open_lots.insert(asset.to_owned(), remaining_lots);
+    }
+
+    uk_pooling::sort_pool_snapshots(&mut pool_snapshots);
+
+    Ok(ProcessedTrades {
+        sale_events,
+        open_lots,
+        shortfalls,
+        pool_snapshots,
+        ..Default::default()
+    })
+}
+
 
+/// Matches the trades before the cut-over without venues, then the trades after it per venue,
+/// starting from the allocated lots rather than the lots left by the first pass
+fn process_with_safe_harbor(all_trades: SortedTrades, config: &Config, safe_harbor: &SafeHarborConfig) -> Result<ProcessedTrades, CryptotaxError> {
+    let (mut before, mut after): (SortedTrades, SortedTrades) = (BTreeMap::new(), BTreeMap::new());
+    for (asset, trades) in all_trades {
+        let (earlier, later): (Vec<Trade>, Vec<Trade>) = trades.into_iter().partition(|trade| trade.trade_time < safe_harbor.cutover);
+        before.insert(asset.to_owned(), earlier);
+        after.insert(asset, later);
+    }
+    before.retain(|_, trades| !trades.is_empty());
+
+    let universal = Config { venues: None, safe_harbor: None, ..config.clone() };
+    let mut processed = match_trades(before, &universal)?;
 
-    for (asset, trades) in all_trades.iter() {
-        
-        let mut buy_txn_list = build_buy_list(trades, &config.accounting_type); // Filter and sort buys based on accounting type (eg FIFO)
-        
-        
-        let mut sale_txn_list = build_sale_list(trades); // Filter and sort sales chronologically
+    seed_lots(&mut after, &import_starting_lots(&safe_harbor.filepath)?, &config.reporting_currency);
+    after.retain(|_, trades| !trades.is_empty());
 
-        if sale_txn_list.is_empty() {
-            continue; // Skip asset if no sales
+    let per_venue = Config { safe_harbor: None, ..config.clone() };
+    let later = match_trades(after, &per_venue)?;
+
+    processed.sale_events.extend(later.sale_events);
+    processed.shortfalls.extend(later.shortfalls);
+    processed.pool_snapshots.extend(later.pool_snapshots);
+    uk_pooling::sort_pool_snapshots(&mut processed.pool_snapshots);
+    processed.open_lots = later.open_lots;
+    Ok(processed)
+}
+
+
+/// Matches each sale against individual buy lots in the order given by the accounting type (eg FIFO, HTFO),
+/// after consuming any lots assigned to the sale by txn id. Returns the buy lots with a remaining balance
+fn match_lots(
+    trades: &[Trade],
+    config: &Config,
+    acct_type: &AccountingType,
+    assignments: &HashMap<String, Vec<LotAssignment>>,
+    sale_events: &mut Vec<SaleEvent>,
+    shortfalls: &mut Vec<Shortfall>,
+) -> Result<Vec<Trade>, CryptotaxError> {
+    if config.venues.is_some() {
+        return wallets::match_lots_by_venue(trades, config, acct_type, assignments, sale_events, shortfalls);
+    }
+
+
+    let mut buy_txn_list = build_buy_list(trades, acct_type, config.tie_breaker); // Filter and sort buys based on accounting type (eg FIFO)
+    let mut opening_lot = trades.first().and_then(|trade| opening_balance_lot(config, &trade.base_asset, &trade.quote_asset));
+    
+    
+    let mut sale_txn_list = build_sale_list(trades, config.tie_breaker); // Filter and sort sales chronologically
+
+    for sale in sale_txn_list.iter_mut() {
+        if let Some(assigned) = sale.txn_id.as_ref().and_then(|id| assignments.get(id)) {
+            apply_assignments(sale, &mut buy_txn_list, assigned, config.holding_period.long_term_months, sale_events)?;
         }
 
-        for sale in sale_txn_list.iter_mut() {
-            for buy in buy_txn_list.iter_mut() {
+        if sale.remaining < DUST_THRESHOLD {
+            continue;
+        }
 
-                // Filter for invalid buy transactions
-                if buy.unix_time > sale.unix_time || buy.remaining < dust_threshold {
-                    continue;
-                }
+        consume_lots(&mut buy_txn_list, sale, acct_type, config, sale_events);
 
-                let clip_size = cmp::min(buy.remaining, sale.remaining);
-                let event = SaleEvent::new(buy, sale, clip_size);
+        resolve_shortfall(sale, config, opening_lot.as_mut(), sale_events, shortfalls)?;
+    }
 
-                sale_events.push(event);
+    buy_txn_list.extend(opening_lot);
+    buy_txn_list.retain(|buy| buy.remaining >= DUST_THRESHOLD);
+    Ok(buy_txn_list)
+}
 
-                buy.remaining -= clip_size;
-                sale.remaining -= clip_size;
 
-                if sale.remaining < dust_threshold {
-                    break;
-                }
-                
-                if buy.remaining < dust_threshold {
-                    continue;
-                }
-            }
+/// Matches a sale against the buy lots in the order given by the accounting type, until the sale or the lots run out
+pub(crate) fn consume_lots(buy_list: &mut [Trade], sale: &mut Trade, acct_type: &AccountingType, config: &Config, sale_events: &mut Vec<SaleEvent>) {
+
+    for i in order_lots(buy_list, sale, acct_type, config) {
+        let buy = &mut buy_list[i];
+
+        let clip_size = cmp::min(buy.remaining, sale.remaining);
+        let event = SaleEvent::new(buy, sale, clip_size, config.holding_period.long_term_months);
+
+        sale_events.push(event);
+
+        buy.remaining -= clip_size;
+        sale.remaining -= clip_size;
+
+        if sale.remaining < DUST_THRESHOLD {
+            break;
         }
+    }
+}
+
 
-        // let asset, cost_basis = 
-        let (asset, cost_basis) = calc_cost_basis(&buy_txn_list);
-        cost_bases.insert(asset, cost_basis);
+/// Matches each sale against a single pool per asset, where every buy updates the pool's average cost.
+/// Returns the remaining pool as a single lot, dated at the first acquisition since the pool was last emptied
+fn match_average_cost(
+    trades: &[Trade],
+    config: &Config,
+    sale_events: &mut Vec<SaleEvent>,
+    shortfalls: &mut Vec<Shortfall>,
+) -> Result<Vec<Trade>, CryptotaxError> {
+    let mut opening_lot = trades.first().and_then(|trade| opening_balance_lot(config, &trade.base_asset, &trade.quote_asset));
 
+    // Acquisitions and sales in the configured total order
+    let mut txn_list: Vec<Trade> = trades
+        .iter()
+        .filter(|trade| trade.txn_type.is_acquisition() || trade.txn_type == TxnType::Sale)
+        .cloned()
+        .collect();
+    txn_list.sort_by(|a, b| a.cmp_chronological(b, config.tie_breaker));
+
+    let mut pool: Option<Trade> = None;
+
+    for mut txn in txn_list {
+        match (txn.txn_type.clone(), pool.as_mut()) {
+            (TxnType::Buy | TxnType::Income(_), Some(pooled)) if pooled.remaining >= DUST_THRESHOLD => pooled.add_to_pool(&txn),
+            (TxnType::Buy | TxnType::Income(_), _) => pool = Some(txn.as_pool()),
+            (TxnType::Sale, Some(pooled)) if pooled.remaining >= DUST_THRESHOLD => {
+                let clip_size = cmp::min(pooled.remaining, txn.remaining);
+                let mut event = SaleEvent::new(pooled, &txn, clip_size, config.holding_period.long_term_months);
+                event.match_rule = MatchRule::Pool;
+
+                sale_events.push(event);
+
+                pooled.remove_from_pool(clip_size);
+                txn.remaining -= clip_size;
+            }
+            _ => {}
+        }
+
+        if txn.txn_type == TxnType::Sale {
+            resolve_shortfall(&mut txn, config, opening_lot.as_mut(), sale_events, shortfalls)?;
+        }
     }
-    (sale_events, cost_bases)
+
+    Ok(pool.into_iter().chain(opening_lot).filter(|pooled| pooled.remaining >= DUST_THRESHOLD).collect())
 }
 
-fn calc_cost_basis(buys: &[Trade]) -> (String, f32) {
-    let total_cost = buys.iter().map(|buy| buy.price * buy.remaining.to_f32().unwrap()).sum::<f32>();
-    let mut total_shares = buys.iter().map(|buy| buy.remaining).sum::<Decimal>().to_f32().unwrap();
+/// Average cost per unit, including purchase fees, of the remaining balance of a set of buy lots.
+/// None if only dust remains
+pub fn calc_cost_basis(buys: &[Trade]) -> Option<Decimal> {
+    let total_cost = buys.iter().map(|buy| buy.pro_rata(buy.quote_asset_amount + buy.fee, buy.remaining)).sum::<Decimal>();
+    let total_shares = buys.iter().map(|buy| buy.remaining).sum::<Decimal>();
     
-    // If dust amount set to NaN
-    if total_shares < 0.00001 {
-        total_shares = std::f32::NAN;
+    // No meaningful average for a dust amount
+    if total_shares < DUST_THRESHOLD {
+        return None;
     }
     
-    let cost_basis = total_cost / total_shares;
-
-    (buys[0].base_asset.to_owned(), cost_basis)
+    Some(total_cost / total_shares)
 }
 
 
-pub fn get_annual_summary(sales: &[SaleEvent]) -> DataFrame {
+/// Summarises gain-loss per asset (rows) and sell year, split into short- and long-term (columns).
+/// Totals are summed exactly, then rounded
+pub fn get_annual_summary(sales: &[SaleEvent], rounding: &Rounding) -> Result<DataFrame, CryptotaxError> {
 
     let unique_assets: Vec<String> = sales
         .iter()
@@ -159,36 +441,37 @@ pub fn get_annual_summary(sales: &[SaleEvent]) -> DataFrame {
         .sorted()
         .collect();
 
-    // Empty Map of <Year<Asset, Gain-Loss>>
-    let mut map: BTreeMap<i32, BTreeMap<String, f32>> = BTreeMap::default();
+    // Empty Map of <(Year, Holding Period)<Asset, Gain-Loss>>
+    let mut map: BTreeMap<(i32, HoldingPeriod), BTreeMap<String, Decimal>> = BTreeMap::default();
 
     // Create a default inner Map for each year
-    let default_empty_dict: BTreeMap<String, f32> = unique_assets
+    let default_empty_dict: BTreeMap<String, Decimal> = unique_assets
         .iter()
-        .map(|asset| (asset.to_owned(), 0.0))
+        .map(|asset| (asset.to_owned(), Decimal::ZERO))
         .collect();
 
     for year in unique_years {
-        map.insert(year, default_empty_dict.clone());
+        map.insert((year, HoldingPeriod::ShortTerm), default_empty_dict.clone());
+        map.insert((year, HoldingPeriod::LongTerm), default_empty_dict.clone());
     }
 
     // Update gain-loss for each sale
     for sale in sales.iter() {
-        let val = map.get_mut(&sale.sell_year).unwrap().get_mut(&sale.name).unwrap();
+        let val = map.get_mut(&(sale.sell_year, sale.holding_period)).unwrap().get_mut(&sale.name).unwrap();
         *val += sale.gain_loss;
     }
 
     // Initialize Dataframe with Unique Assets
     let asset_series = Series::new("Asset Name", unique_assets);
-    let mut df = DataFrame::new(vec![asset_series]).unwrap();
+    let mut df = DataFrame::new(vec![asset_series])?;
 
     // Loop over years to populate data
-    for (k,v) in map {
-        let _series = Series::new(&k.to_string(), v.values().cloned().collect::<Vec<f32>>());
-        df.with_column(_series).unwrap();
+    for ((year, holding_period), v) in map {
+        let _series = decimal_series(&format!("{} {}", year, holding_period), v.values().cloned(), rounding);
+        df.with_column(_series)?;
     }
 
-    df
+    Ok(df)
 
 }
 
@@ -196,34 +479,51 @@ pub fn get_annual_summary(sales: &[SaleEvent]) -> DataFrame {
 
 
 
+/// Builds a polars Decimal series with a fixed scale from the rounded values
+pub(crate) fn decimal_series(name: &str, values: impl Iterator<Item = Decimal>, rounding: &Rounding) -> Series {
+    let mantissas: Vec<i128> = values
+        .map(|value| rounding.apply(value).mantissa())
+        .collect();
+
+    Int128Chunked::from_vec(name, mantissas)
+        .into_decimal_unchecked(None, rounding.decimal_places as usize)
+        .into_series()
+}
+
+
 /// Builds a list of buys from the given list of trades, sorted by the specified accounting type.
 ///
 /// # Arguments
 ///
 /// * `trades` - The list of trades to build the buy list from.
 /// * `analysis_type` - The accounting type to sort the buy list by.
+/// * `tie_breaker` - Order of buys with the same timestamp, or the same price for HIFO / LOFO.
 ///
 /// # Returns
 ///
 /// A list of buys, sorted by the specified accounting type.
 ///
-pub fn build_buy_list(trades: &[Trade], acct_type: &AccountingType) -> Vec<Trade> {
+pub fn build_buy_list(trades: &[Trade], acct_type: &AccountingType, tie_breaker: TieBreaker) -> Vec<Trade> {
     let mut buy_list: Vec<Trade> = trades
         .iter()
-        .filter(|trade| trade.txn_type == TxnType::Buy)
+        .filter(|trade| trade.txn_type.is_acquisition())
         .cloned()
         .collect();
 
-    if buy_list.is_empty() {
-        panic!("ERROR: No buy transactions found");
-    }
+    sort_lots(&mut buy_list, acct_type, tie_breaker);
+    buy_list
+}
+
 
+/// Sorts buy lots by the specified accounting type. Equal lots fall back to chronological order
+pub(crate) fn sort_lots(buy_list: &mut [Trade], acct_type: &AccountingType, tie_breaker: TieBreaker) {
     match acct_type {
-        AccountingType::FIFO => buy_list.sort_by_key(|k| k.trade_time),
-        AccountingType::LIFO => buy_list.sort_by_key(|k| Reverse(k.trade_time)),
-        AccountingType::HIFO => buy_list.sort_by(|a, b| b.price.partial_cmp(&a.price).unwrap()),
+        AccountingType::FIFO | AccountingType::ACB | AccountingType::UkPooling | AccountingType::SpecificId => buy_list.sort_by(|a, b| a.cmp_chronological(b, tie_breaker)),
+        AccountingType::LIFO => buy_list.sort_by(|a, b| b.cmp_chronological(a, tie_breaker)),
+        AccountingType::HIFO => buy_list.sort_by(|a, b| b.price.cmp(&a.price).then(a.cmp_chronological(b, tie_breaker))),
+        AccountingType::LOFO => buy_list.sort_by(|a, b| a.price.cmp(&b.price).then(a.cmp_chronological(b, tie_breaker))),
+        AccountingType::HTFO | AccountingType::MinTax => buy_list.sort_by(|a, b| a.cmp_chronological(b, tie_breaker)), // Reordered per sale by `order_lots`
     }
-    buy_list
 }
 
 
@@ -232,22 +532,23 @@ pub fn build_buy_list(trades: &[Trade], acct_type: &AccountingType) -> Vec<Trade
 /// # Arguments
 ///
 /// * `trades` - The list of trades to build the sale list from.
+/// * `tie_breaker` - Order of sales with the same timestamp.
 ///
 /// # Returns
 ///
 /// A list of sales, sorted by trade time.
-fn build_sale_list(trades: &[Trade]) -> Vec<Trade> {
+fn build_sale_list(trades: &[Trade], tie_breaker: TieBreaker) -> Vec<Trade> {
     let mut sale_list: Vec<Trade> = trades
         .iter()
         .filter(|trade| trade.txn_type == TxnType::Sale)
         .cloned()
         .collect();
 
-    sale_list.sort_by_key(|k| k.trade_time);
+    sale_list.sort_by(|a, b| a.cmp_chronological(b, tie_breaker));
     sale_list
 }
 
-fn serialize_datetime<S>(dt: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error>
+pub(crate) fn serialize_datetime<S>(dt: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
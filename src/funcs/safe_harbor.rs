@@ -0,0 +1,96 @@
+//! Safe-harbor transition from universal (pooled) to per-wallet tracking. The lots left open at the cut-over
+//! date are allocated to the wallets holding the actual balances, and become the wallets' starting lots
+
+use rust_decimal::Decimal;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::funcs::csv_util::{parse_decimal, read_records};
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::process_trades::{OpenLots, DUST_THRESHOLD};
+use crate::funcs::starting_lots::StartingLot;
+use crate::funcs::trade::Trade;
+
+
+#[derive(thiserror::Error, Debug)]
+pub enum SafeHarborError {
+    #[error("ERROR: Open {asset} lots total {open_lots}, but wallet balances total {balances}")]
+    BalanceMismatch { asset: String, open_lots: Decimal, balances: Decimal },
+}
+
+
+/// Actual holding of an asset in a wallet at the cut-over date
+#[derive(Debug, Clone)]
+pub struct WalletBalance {
+    pub wallet: String,
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct WalletBalanceRecord {
+    wallet: String,
+    asset: String,
+    amount: String,
+}
+
+
+/// Imports a csv with `wallet,asset,amount` columns
+pub fn import_wallet_balances(filepath: &Path) -> Result<Vec<WalletBalance>, CryptotaxError> {
+    read_records(filepath, |record: WalletBalanceRecord| Ok(WalletBalance {
+        amount: parse_decimal("amount", &record.amount)?,
+        wallet: record.wallet,
+        asset: record.asset,
+    }))
+}
+
+
+/// Allocates each asset's open lots to its wallets. Deterministic: the oldest lots (then by txn id and cost)
+/// fill the wallets in name order, splitting a lot across wallets where needed.
+/// The open lots and the wallet balances must agree on the total held of every asset
+pub fn allocate_lots(open_lots: &OpenLots, balances: &[WalletBalance]) -> Result<Vec<StartingLot>, SafeHarborError> {
+
+    let assets: BTreeSet<&String> = open_lots.keys().chain(balances.iter().map(|balance| &balance.asset)).collect();
+    let mut allocation: Vec<StartingLot> = vec![];
+
+    for asset in assets {
+        let mut lots: Vec<&Trade> = open_lots.get(asset).into_iter().flatten().filter(|lot| lot.remaining >= DUST_THRESHOLD).collect();
+        lots.sort_by(|a, b| (a.trade_time, &a.txn_id, a.price).cmp(&(b.trade_time, &b.txn_id, b.price)));
+
+        let mut wallets: Vec<&WalletBalance> = balances.iter().filter(|balance| &balance.asset == asset).collect();
+        wallets.sort_by(|a, b| a.wallet.cmp(&b.wallet));
+
+        let total_lots: Decimal = lots.iter().map(|lot| lot.remaining).sum();
+        let total_balances: Decimal = wallets.iter().map(|balance| balance.amount).sum();
+        if (total_lots - total_balances).abs() >= DUST_THRESHOLD {
+            return Err(SafeHarborError::BalanceMismatch { asset: asset.to_owned(), open_lots: total_lots, balances: total_balances });
+        }
+
+        let mut lots = lots.into_iter().map(|lot| (lot, lot.remaining)).peekable();
+        for wallet in wallets {
+            let mut unfilled = wallet.amount;
+
+            while unfilled >= DUST_THRESHOLD {
+                let Some((lot, available)) = lots.peek_mut() else { break };
+                let clip_size = unfilled.min(*available);
+
+                allocation.push(StartingLot {
+                    asset: asset.to_owned(),
+                    acquired: lot.trade_time,
+                    amount: clip_size,
+                    cost_basis: lot.pro_rata(lot.quote_asset_amount + lot.fee, clip_size),
+                    wallet: Some(wallet.wallet.to_owned()),
+                    txn_id: lot.txn_id.to_owned(),
+                });
+
+                unfilled -= clip_size;
+                *available -= clip_size;
+                if *available < DUST_THRESHOLD {
+                    lots.next();
+                }
+            }
+        }
+    }
+
+    Ok(allocation)
+}
@@ -0,0 +1,108 @@
+//! Specific identification: a user-supplied file dictates which buy lots each sale consumes
+
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::funcs::csv_util::{parse_decimal, read_records};
+use crate::funcs::process_trades::{MatchRule, SaleEvent, DUST_THRESHOLD};
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::trade::Trade;
+
+
+#[derive(thiserror::Error, Debug)]
+pub enum LotAssignmentError {
+    #[error("ERROR: Sale {sale} is assigned to buy {buy}, which was not found for {asset}")]
+    UnknownLot { sale: String, buy: String, asset: String },
+    #[error("ERROR: Sale {sale} is assigned to buy {buy}, which was acquired after the sale")]
+    AcquiredAfterSale { sale: String, buy: String },
+    #[error("ERROR: Sale {sale} is assigned {requested} of buy {buy}, but only {available} remains")]
+    LotExhausted { sale: String, buy: String, requested: Decimal, available: Decimal },
+    #[error("ERROR: Sale {sale} is assigned {requested} in total, but only sold {available}")]
+    ExceedsSale { sale: String, requested: Decimal, available: Decimal },
+}
+
+
+/// One row of the lot-assignment file
+#[derive(Debug, Clone)]
+pub struct LotAssignment {
+    pub sale_txn_id: String,
+    pub buy_txn_id: String,
+    pub amount: Decimal,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct LotAssignmentRecord {
+    sale_txn_id: String,
+    buy_txn_id: String,
+    amount: String,
+}
+
+
+/// Imports a csv with `sale_txn_id,buy_txn_id,amount` columns, grouped by sale txn id in file order
+pub fn import_lot_assignments(filepath: &Path) -> Result<HashMap<String, Vec<LotAssignment>>, CryptotaxError> {
+    let records = read_records(filepath, |record: LotAssignmentRecord| Ok(LotAssignment {
+        amount: parse_decimal("amount", &record.amount)?,
+        sale_txn_id: record.sale_txn_id,
+        buy_txn_id: record.buy_txn_id,
+    }))?;
+
+    let mut assignments: HashMap<String, Vec<LotAssignment>> = HashMap::new();
+    for assignment in records {
+        assignments.entry(assignment.sale_txn_id.to_owned()).or_default().push(assignment);
+    }
+    Ok(assignments)
+}
+
+
+/// Consumes the buy lots assigned to a sale. Any unassigned quantity is left in `sale.remaining`
+/// for the default method to match
+pub fn apply_assignments(
+    sale: &mut Trade,
+    buy_list: &mut [Trade],
+    assignments: &[LotAssignment],
+    long_term_months: u32,
+    sale_events: &mut Vec<SaleEvent>,
+) -> Result<(), LotAssignmentError> {
+    let sale_id = sale.txn_id.clone().unwrap_or_default();
+
+    let total_assigned: Decimal = assignments.iter().map(|assignment| assignment.amount).sum();
+    if total_assigned - sale.remaining >= DUST_THRESHOLD {
+        return Err(LotAssignmentError::ExceedsSale { sale: sale_id, requested: total_assigned, available: sale.remaining });
+    }
+
+    for assignment in assignments {
+        let buy = buy_list
+            .iter_mut()
+            .find(|buy| buy.txn_id.as_deref() == Some(assignment.buy_txn_id.as_str()))
+            .ok_or_else(|| LotAssignmentError::UnknownLot {
+                sale: sale_id.to_owned(),
+                buy: assignment.buy_txn_id.to_owned(),
+                asset: sale.base_asset.to_owned(),
+            })?;
+
+        if buy.unix_time > sale.unix_time {
+            return Err(LotAssignmentError::AcquiredAfterSale { sale: sale_id, buy: assignment.buy_txn_id.to_owned() });
+        }
+
+        if assignment.amount - buy.remaining >= DUST_THRESHOLD {
+            return Err(LotAssignmentError::LotExhausted {
+                sale: sale_id,
+                buy: assignment.buy_txn_id.to_owned(),
+                requested: assignment.amount,
+                available: buy.remaining,
+            });
+        }
+
+        let clip_size = assignment.amount.min(buy.remaining).min(sale.remaining);
+        let mut event = SaleEvent::new(buy, sale, clip_size, long_term_months);
+        event.match_rule = MatchRule::SpecificId;
+
+        sale_events.push(event);
+
+        buy.remaining -= clip_size;
+        sale.remaining -= clip_size;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! Lots held before the first processed trade, eg carried in from a prior system or a safe-harbor allocation to wallets.
+//! They are read from and written to a csv of `asset,acquired,amount,cost_basis,wallet,txn_id`
+
+use chrono::NaiveDateTime;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::path::Path;
+
+use crate::funcs::csv_util::{parse_decimal, read_records};
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::import_trades::SortedTrades;
+use crate::funcs::process_trades::serialize_datetime;
+use crate::funcs::trade::{parse_datetime_string, FieldError, Trade};
+
+
+/// A lot carried in from before processing starts
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StartingLot {
+    pub asset: String,
+    #[serde(serialize_with = "serialize_datetime")]
+    pub acquired: NaiveDateTime,
+    pub amount: Decimal,
+    pub cost_basis: Decimal, // Total, including purchase fees
+    pub wallet: Option<String>, // Venue the lot is held on, when lots are tracked per venue
+    pub txn_id: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct StartingLotRecord {
+    asset: String,
+    acquired: String,
+    amount: String,
+    cost_basis: String,
+    #[serde(default)]
+    wallet: Option<String>,
+    #[serde(default)]
+    txn_id: Option<String>,
+}
+
+
+/// Imports a starting-lots csv. The wallet and txn_id columns are optional
+pub fn import_starting_lots(filepath: &Path) -> Result<Vec<StartingLot>, CryptotaxError> {
+    read_records(filepath, parse_record)
+}
+
+
+fn parse_record(record: StartingLotRecord) -> Result<StartingLot, FieldError> {
+    let non_empty = |field: Option<String>| field.filter(|value| !value.is_empty());
+
+    Ok(StartingLot {
+        acquired: parse_datetime_string(&record.acquired).map_err(|e| FieldError::new("acquired", e))?,
+        amount: parse_decimal("amount", &record.amount)?,
+        cost_basis: parse_decimal("cost_basis", &record.cost_basis)?,
+        asset: record.asset,
+        wallet: non_empty(record.wallet),
+        txn_id: non_empty(record.txn_id),
+    })
+}
+
+
+/// Adds the starting lots to the trades as buys, so they enter the buy lists like any other acquisition
+pub fn seed_lots(all_trades: &mut SortedTrades, lots: &[StartingLot], quote_asset: &str) {
+    for lot in lots {
+        let mut trade = Trade::synthetic_lot(&lot.asset, quote_asset, lot.amount, lot.cost_basis, lot.acquired, lot.txn_id.to_owned());
+        trade.venue = lot.wallet.to_owned();
+        all_trades.entry(lot.asset.to_owned()).or_default().push(trade);
+    }
+}
@@ -0,0 +1,82 @@
+//! Atomic swaps. Rows sharing a txn id, with at least one sale and one buy between them, are the legs of a
+//! single exchange: they must balance, take one fair value from the more reliable side, and happen together
+
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+
+use crate::funcs::config::{Config, SwapValuation};
+use crate::funcs::price_source::PriceHistory;
+use crate::funcs::trade::Trade;
+use crate::funcs::txn_type::TxnType;
+
+
+#[derive(thiserror::Error, Debug)]
+pub enum SwapError {
+    #[error("ERROR: Swap {txn_id} gives up {disposed} but receives {acquired}, further apart than the swap tolerance")]
+    Unbalanced { txn_id: String, disposed: Decimal, acquired: Decimal },
+    #[error("ERROR: Swap {txn_id} has legs quoted in different assets")]
+    MixedQuoteAssets { txn_id: String },
+}
+
+
+/// Links the legs of each swap among the imported rows. Every leg is moved to the earliest leg's timestamp,
+/// so the acquisition is never applied before the disposal that paid for it, even when the rows' times differ.
+/// The fair value is split across each side's legs in proportion to their stated values
+pub fn link_swaps(trades: &mut [Trade], config: &Config, price_history: Option<&PriceHistory>) -> Result<(), SwapError> {
+    let mut swaps: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, trade) in trades.iter().enumerate() {
+        if let (Some(txn_id), TxnType::Buy | TxnType::Sale) = (&trade.txn_id, &trade.txn_type) {
+            swaps.entry(txn_id.to_owned()).or_default().push(i);
+        }
+    }
+
+    for (txn_id, legs) in swaps {
+        let (disposals, acquisitions): (Vec<usize>, Vec<usize>) = legs.iter().partition(|&&i| trades[i].txn_type == TxnType::Sale);
+        if disposals.is_empty() || acquisitions.is_empty() {
+            continue;
+        }
+        if legs.iter().any(|&i| trades[i].quote_asset != trades[legs[0]].quote_asset) {
+            return Err(SwapError::MixedQuoteAssets { txn_id });
+        }
+
+        let stated = |side: &[usize]| side.iter().map(|&i| trades[i].quote_asset_amount).sum::<Decimal>();
+        let (disposed, acquired) = (stated(&disposals), stated(&acquisitions));
+        if (disposed - acquired).abs() > disposed.max(acquired) * config.swaps.tolerance {
+            return Err(SwapError::Unbalanced { txn_id, disposed, acquired });
+        }
+
+        let fair_value = match config.swaps.fair_value {
+            SwapValuation::Auto => market_value(trades, &disposals, config, price_history)
+                .or_else(|| market_value(trades, &acquisitions, config, price_history))
+                .unwrap_or(disposed),
+            SwapValuation::Disposal => disposed,
+            SwapValuation::Acquisition => acquired,
+        };
+        let swap_time = legs.iter().map(|&i| trades[i].trade_time).min().unwrap_or_default();
+
+        for (side, side_value) in [(&disposals, disposed), (&acquisitions, acquired)] {
+            let even_share = Decimal::ONE / Decimal::from(side.len());
+            for &i in side {
+                let leg = &mut trades[i];
+                let share = leg.quote_asset_amount.checked_div(side_value).unwrap_or(even_share);
+
+                leg.quote_asset_amount = fair_value * share;
+                leg.price = leg.quote_asset_amount / leg.base_asset_amount;
+                leg.trade_time = swap_time;
+                leg.unix_time = swap_time.and_utc().timestamp();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Value of one side of a swap at market prices, when every leg is priced in the legs' quote asset
+fn market_value(trades: &[Trade], side: &[usize], config: &Config, price_history: Option<&PriceHistory>) -> Option<Decimal> {
+    let history = price_history.filter(|_| trades[side[0]].quote_asset == config.price_source.currency)?;
+
+    side.iter()
+        .map(|&i| history.price_at(&trades[i].base_asset, trades[i].trade_time).map(|price| price * trades[i].base_asset_amount))
+        .sum()
+}
@@ -1,9 +1,10 @@
 
-use chrono::NaiveDateTime;
-use rust_decimal::{prelude::ToPrimitive, Decimal};
-use std::error::Error;
+use chrono::{NaiveDate, NaiveDateTime};
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
 
-use crate::funcs::config::Config;
+use crate::funcs::config::{Config, TieBreaker};
+use crate::funcs::transfers::TransferLink;
 use crate::funcs::txn_type::TxnType;
 use crate::funcs::import_trades::CsvRecord;
 
@@ -17,12 +18,51 @@ pub enum ValueParseError {
         value: String,
         #[source]
         source: rust_decimal::Error,
+    },
+    #[error("ERROR: Fee of {amount} {asset} needs a fee_value in the quote asset")]
+    MissingFeeValueError {
+        amount: Decimal,
+        asset: String,
+    },
+    #[error("ERROR: Buy or sell of {0} with a zero amount has no price")]
+    ZeroAmountError(String),
+    #[error("ERROR: Venue {0} is not listed in [venues]")]
+    UnknownVenueError(String),
+    #[error("ERROR: Could not read exchange export row: {0}")]
+    ExchangeFormatError(String),
+    #[error("ERROR: Row has {found} fields but the header has {expected}")]
+    FieldCountError {
+        expected: usize,
+        found: usize,
+    },
+    #[error("ERROR: Could not read row: {0}")]
+    RecordError(String),
+    #[error("ERROR: No price for {asset} at {time} to value it as income. Add it to [price_source]")]
+    MissingPriceError {
+        asset: String,
+        time: NaiveDateTime,
+    },
+}
+
+/// A ValueParseError tagged with the csv column it came from
+#[derive(thiserror::Error, Debug)]
+#[error("column '{column}': {source}")]
+pub struct FieldError {
+    pub column: String,
+    #[source]
+    pub source: ValueParseError,
+}
+
+impl FieldError {
+    pub(crate) fn new(column: &str, source: ValueParseError) -> Self {
+        FieldError { column: column.to_string(), source }
     }
 }
 
 /// Trade represents each unique buy/sell transaction. Created from a CsvRecord and Config 
 #[derive(Debug, Clone)]
 pub struct Trade {
+    pub txn_id: Option<String>,
     pub trade_time: NaiveDateTime,
     pub txn_type: TxnType,
     pub base_asset: String,
@@ -31,41 +71,97 @@ pub struct Trade {
     pub quote_asset_amount: Decimal,
     pub remaining: Decimal,
     pub unix_time: i64,
-    pub price: f32,
+    pub price: Decimal,
+    pub fee: Decimal, // Fee valued in the quote asset
+    pub fee_asset: String,
+    pub fee_asset_amount: Decimal,
+    pub fx_currency: String, // Currency the trade was quoted in before conversion to the reporting currency
+    pub fx_rate: Decimal, // Reporting currency per unit of `fx_currency`. 1 when not converted
+    pub venue: Option<String>, // Exchange or wallet the trade happened on
+    pub notes: Option<String>,
+    pub tx_hash: Option<String>, // On-chain transaction hash
+    pub transfer: Option<TransferLink>, // Set on a withdrawal once it is paired with a deposit
+    pub unclassified_txn_type: Option<String>, // Raw type string, when no rule or list classified the row
+    pub source_row: u64, // Line in the trades csv. 0 for lots that don't come from it
 }
 
 impl Trade {
-    pub fn new(record: CsvRecord, config: &Config) -> Result<Self, Box<dyn Error>> {
-        
+    pub fn new(record: CsvRecord, config: &Config) -> Result<Self, FieldError> {
+        let classified = TxnType::classify(&record, config);
+        Self::with_txn_type(record, classified, config)
+    }
+
+    /// Builds a trade from a record whose type is already known, eg from a native exchange export.
+    /// None is treated as Other and reported as unclassified
+    pub fn with_txn_type(record: CsvRecord, classified: Option<TxnType>, config: &Config) -> Result<Self, FieldError> {
+
         // NaiveDateTime interpolation
-        let trade_time = parse_datetime_string(&record.timestamp)?;
-        let unix_time: i64 = NaiveDateTime::timestamp(&trade_time);
+        let trade_time = parse_datetime_string(&record.timestamp)
+            .map_err(|e| FieldError::new("timestamp", e))?;
+        let unix_time: i64 = trade_time.and_utc().timestamp();
 
         // TxnType interpolation
-        let txn_type = TxnType::return_txn_type(
-            &record.txn_type,
-            &config.buy_txn_types,
-            &config.sell_txn_types,
-        );
+        let unclassified_txn_type = classified.is_none().then(|| record.txn_type.trim().to_string());
+        let txn_type = classified.unwrap_or(TxnType::Other);
 
         // Decimal conversions
+        // Signed exports give disposals as negative amounts. The sign only matters for classifying the row
         let base_asset_amount: Decimal = record.base_asset_amount.parse::<Decimal>().
-            map_err(|e| ValueParseError::DecimalParseError { value: record.base_asset_amount.clone(), source: e })?;
+            map_err(|e| FieldError::new("base_asset_amount", ValueParseError::DecimalParseError { value: record.base_asset_amount.clone(), source: e }))?
+            .abs();
 
-        let quote_asset_amount: Decimal = record.quote_asset_amount.parse::<Decimal>().
-            map_err(|e| ValueParseError::DecimalParseError { value: record.quote_asset_amount.clone(), source: e })?;
+        // Transfers often have no value in the csv
+        let quote_asset_amount: Decimal = match txn_type {
+            TxnType::Deposit | TxnType::Withdrawal | TxnType::Other if record.quote_asset_amount.trim().is_empty() => Decimal::ZERO,
+            _ => record.quote_asset_amount.parse::<Decimal>().
+                map_err(|e| FieldError::new("quote_asset_amount", ValueParseError::DecimalParseError { value: record.quote_asset_amount.clone(), source: e }))?
+                .abs(),
+        };
         
-        // Price
-        let price = quote_asset_amount
-            .checked_div(base_asset_amount)
-            .and_then(|div_result| div_result.to_f32())
-            .unwrap();
+        // Price. A zero amount only makes sense for transactions that are never matched
+        let price = match quote_asset_amount.checked_div(base_asset_amount) {
+            Some(price) => price,
+            None if txn_type == TxnType::Other => Decimal::ZERO,
+            None => return Err(FieldError::new("base_asset_amount", ValueParseError::ZeroAmountError(record.base_asset.to_owned()))),
+        };
+
+        // Fees. Default to the quote asset when no fee asset is given
+        let fee_asset_amount: Decimal = parse_optional_decimal(&record.fee_amount).map_err(|e| FieldError::new("fee_amount", e))?.unwrap_or_default();
+        let fee_asset: String = match record.fee_asset.as_deref().map(str::trim) {
+            Some(asset) if !asset.is_empty() => asset.to_string(),
+            _ => record.quote_asset.to_owned(),
+        };
+        let fee_value: Option<Decimal> = parse_optional_decimal(&record.fee_value).map_err(|e| FieldError::new("fee_value", e))?;
+
+        let fee: Decimal = match fee_value {
+            _ if fee_asset_amount.is_zero() => Decimal::ZERO,
+            _ if fee_asset == record.quote_asset => fee_asset_amount,
+            Some(value) => value,
+            None if fee_asset == record.base_asset => fee_asset_amount * price,
+            None => return Err(FieldError::new("fee_value", ValueParseError::MissingFeeValueError { amount: fee_asset_amount, asset: fee_asset })),
+        };
 
         let remaining: Decimal = base_asset_amount; // Field used to represent when a trade was processed in full or part
 
         
+        let txn_id: Option<String> = record.user_txn_id
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty());
+
+        let fx_currency = record.quote_asset.to_owned();
+        let venue: Option<String> = record.venue
+            .map(|venue| venue.trim().to_string())
+            .filter(|venue| !venue.is_empty());
+
+        if let (Some(venue), Some(known_venues)) = (&venue, &config.venues) {
+            if !known_venues.is_empty() && !known_venues.contains(venue) {
+                return Err(FieldError::new("venue", ValueParseError::UnknownVenueError(venue.to_owned())));
+            }
+        }
+
         // Return
         Ok(Self {
+            txn_id,
             trade_time,
             txn_type,
             base_asset: record.base_asset,
@@ -75,8 +171,173 @@ impl Trade {
             remaining,
             unix_time,
             price,
+            fee,
+            fee_asset,
+            fee_asset_amount,
+            fx_currency,
+            fx_rate: Decimal::ONE,
+            venue,
+            notes: record.notes.filter(|notes| !notes.is_empty()),
+            tx_hash: record.tx_hash.filter(|tx_hash| !tx_hash.is_empty()),
+            transfer: None,
+            unclassified_txn_type,
+            source_row: 0,
+        })
+    }
+
+    /// A buy lot that doesn't come from a csv row, eg an opening balance
+    pub fn synthetic_lot(
+        asset: &str,
+        quote_asset: &str,
+        amount: Decimal,
+        cost_basis: Decimal,
+        acquired: NaiveDateTime,
+        txn_id: Option<String>,
+    ) -> Trade {
+        Trade {
+            txn_id,
+            trade_time: acquired,
+            txn_type: TxnType::Buy,
+            base_asset: asset.to_string(),
+            base_asset_amount: amount,
+            quote_asset: quote_asset.to_string(),
+            quote_asset_amount: cost_basis,
+            remaining: amount,
+            unix_time: acquired.and_utc().timestamp(),
+            price: cost_basis.checked_div(amount).unwrap_or_default(),
+            fee: Decimal::ZERO,
+            fee_asset: quote_asset.to_string(),
+            fee_asset_amount: Decimal::ZERO,
+            fx_currency: quote_asset.to_string(),
+            fx_rate: Decimal::ONE,
+            venue: None,
+            notes: None,
+            tx_hash: None,
+            transfer: None,
+            unclassified_txn_type: None,
+            source_row: 0,
+        }
+    }
+
+    /// A fee paid in anything other than the quote asset is itself a disposal of that asset,
+    /// valued at the fee value. Returns that disposal as a Sale of the fee asset
+    pub fn fee_disposal(&self) -> Option<Trade> {
+        if self.fee_asset == self.quote_asset || self.fee_asset_amount.is_zero() {
+            return None;
+        }
+
+        Some(Trade {
+            txn_id: self.txn_id.as_ref().map(|id| format!("{}-fee", id)),
+            trade_time: self.trade_time,
+            txn_type: TxnType::Sale,
+            base_asset: self.fee_asset.to_owned(),
+            base_asset_amount: self.fee_asset_amount,
+            quote_asset: self.quote_asset.to_owned(),
+            quote_asset_amount: self.fee,
+            remaining: self.fee_asset_amount,
+            unix_time: self.unix_time,
+            price: self.fee / self.fee_asset_amount,
+            fee: Decimal::ZERO,
+            fee_asset: self.quote_asset.to_owned(),
+            fee_asset_amount: Decimal::ZERO,
+            fx_currency: self.fx_currency.to_owned(),
+            fx_rate: self.fx_rate,
+            venue: self.venue.to_owned(),
+            notes: None,
+            tx_hash: self.tx_hash.to_owned(),
+            transfer: None,
+            unclassified_txn_type: None,
+            source_row: self.source_row,
         })
     }
+
+    /// Starts a pooled holding from the remaining balance and cost of this trade
+    pub fn as_pool(&self) -> Trade {
+        let mut pool = self.clone();
+        pool.base_asset_amount = self.remaining;
+        pool.quote_asset_amount = self.pro_rata(self.quote_asset_amount, self.remaining);
+        pool.fee = self.pro_rata(self.fee, self.remaining);
+        pool
+    }
+
+    /// Adds the remaining balance and cost of `other` to this trade, treating it as a pooled holding
+    /// with a single average cost. The pool keeps its original acquisition date, but no longer stands
+    /// for a single row so it loses its txn id and hash
+    pub fn add_to_pool(&mut self, other: &Trade) {
+        self.txn_id = None;
+        self.tx_hash = None;
+        self.quote_asset_amount += other.pro_rata(other.quote_asset_amount, other.remaining);
+        self.fee += other.pro_rata(other.fee, other.remaining);
+        self.base_asset_amount += other.remaining;
+        self.remaining = self.base_asset_amount;
+        self.price = self.quote_asset_amount / self.base_asset_amount;
+    }
+
+    /// Removes `amount` from a pooled holding at the pool's average cost
+    pub fn remove_from_pool(&mut self, amount: Decimal) {
+        self.quote_asset_amount -= self.pro_rata(self.quote_asset_amount, amount);
+        self.fee -= self.pro_rata(self.fee, amount);
+        self.base_asset_amount -= amount;
+        self.remaining = self.base_asset_amount;
+    }
+
+    /// Moves `amount` of the remaining balance into a new lot with the same acquisition date and a pro-rata share of the cost
+    pub fn split_off(&mut self, amount: Decimal) -> Trade {
+        let mut lot = self.clone();
+        lot.base_asset_amount = amount;
+        lot.quote_asset_amount = self.pro_rata(self.quote_asset_amount, amount);
+        lot.fee = self.pro_rata(self.fee, amount);
+        lot.remaining = amount;
+        lot.transfer = None;
+
+        self.remaining -= amount;
+        lot
+    }
+
+    /// Total order of trades: by timestamp, then by the tie-breaker, then by source row
+    pub fn cmp_chronological(&self, other: &Trade, tie_breaker: TieBreaker) -> Ordering {
+        let tie = match tie_breaker {
+            TieBreaker::SourceOrder => Ordering::Equal,
+            TieBreaker::BuysFirst => other.txn_type.is_acquisition().cmp(&self.txn_type.is_acquisition()),
+            TieBreaker::TxnId => match (&self.txn_id, &other.txn_id) {
+                (Some(a), Some(b)) => cmp_txn_ids(a, b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+        };
+
+        self.trade_time.cmp(&other.trade_time).then(tie).then(self.source_row.cmp(&other.source_row))
+    }
+
+    /// Share of a trade total (eg quote amount or fee) attributable to `amount` of the base asset
+    pub fn pro_rata(&self, total: Decimal, amount: Decimal) -> Decimal {
+        if amount == self.base_asset_amount {
+            return total;
+        }
+        total * amount / self.base_asset_amount
+    }
+}
+
+
+/// Numeric ids, as most exchanges issue, compare as numbers so "9" comes before "10". Anything else compares as text
+fn cmp_txn_ids(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u128>(), b.parse::<u128>()) {
+        (Ok(a_num), Ok(b_num)) => a_num.cmp(&b_num).then(a.cmp(b)),
+        _ => a.cmp(b),
+    }
+}
+
+
+/// Parses an optional csv field, treating a missing or blank field as None
+fn parse_optional_decimal(field: &Option<String>) -> Result<Option<Decimal>, ValueParseError> {
+    match field.as_deref().map(str::trim) {
+        Some(value) if !value.is_empty() => value
+            .parse::<Decimal>()
+            .map(Some)
+            .map_err(|e| ValueParseError::DecimalParseError { value: value.to_string(), source: e }),
+        _ => Ok(None),
+    }
 }
 
 
@@ -86,8 +347,7 @@ pub fn parse_datetime_string(datetime: &str) -> Result<NaiveDateTime, ValueParse
         "%Y-%m-%dT%H:%M:%S%.3fZ",
         "%Y-%m-%dT%H:%M:%SZ",
         "%Y-%m-%dT%H:%M:%S",
-        "%Y-%m-%d %H:%M:%S",
-        "%Y-%m-%d",
+        "%Y-%m-%d %H:%M:%S%.f",
     ];
     
     for fmt in common_formats.iter() {
@@ -95,5 +355,10 @@ pub fn parse_datetime_string(datetime: &str) -> Result<NaiveDateTime, ValueParse
             return Ok(dt);
         }
     }
+
+    // Date only, at midnight
+    if let Ok(date) = NaiveDate::parse_from_str(datetime, "%Y-%m-%d") {
+        return Ok(date.and_time(Default::default()));
+    }
     Err(ValueParseError::DatetimeFormatParseError(datetime.to_string()))
 }
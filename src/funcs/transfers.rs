@@ -0,0 +1,169 @@
+//! Transfers between the user's own venues. A withdrawal is paired with the deposit it became, so the
+//! lots carry over with their original basis and acquisition date. Only the network fee is a disposal
+
+use chrono::{Duration, NaiveDateTime};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::funcs::config::Config;
+use crate::funcs::import_trades::SortedTrades;
+use crate::funcs::process_trades::serialize_datetime;
+use crate::funcs::trade::Trade;
+use crate::funcs::txn_type::TxnType;
+
+
+/// A withdrawal paired with its deposit
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchedTransfer {
+    pub asset: String,
+    pub withdrawal_txn_id: Option<String>,
+    pub deposit_txn_id: Option<String>,
+    pub from_venue: Option<String>,
+    pub to_venue: Option<String>,
+    #[serde(serialize_with = "serialize_datetime")]
+    pub withdrawn_at: NaiveDateTime,
+    #[serde(serialize_with = "serialize_datetime")]
+    pub deposited_at: NaiveDateTime,
+    pub amount: Decimal, // Amount received
+    pub network_fee: Decimal, // Amount lost in transit, disposed of at the withdrawal
+}
+
+
+/// A deposit or withdrawal with no counterpart, for manual review
+#[derive(Debug, Clone, Serialize)]
+pub struct UnmatchedTransfer {
+    pub asset: String,
+    pub txn_id: Option<String>,
+    pub venue: Option<String>,
+    #[serde(serialize_with = "serialize_datetime")]
+    pub date: NaiveDateTime,
+    pub direction: TransferDirection,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum TransferDirection {
+    Deposit,
+    Withdrawal,
+}
+
+
+/// Where a paired withdrawal's lots go, when lots are tracked per venue
+#[derive(Debug, Clone)]
+pub struct TransferLink {
+    pub to_venue: Option<String>,
+    pub amount: Decimal, // Amount received, after the network fee
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct TransferReport {
+    pub matched: Vec<MatchedTransfer>,
+    pub unmatched: Vec<UnmatchedTransfer>,
+    pub unvalued_fees: Vec<MatchedTransfer>, // Pairs whose network fee had no value, so it wasn't disposed of
+}
+
+
+/// Pairs each withdrawal with the earliest deposit of the same asset that follows it within the configured
+/// time window, on a different venue when both are known, and received no less than the tolerance allows.
+/// The network fee of each pair is added to `all_trades` as a sale of the asset. A withdrawal with no value,
+/// and no price history to value it at, can't price its fee, so the pair is reported instead of selling at zero
+pub fn match_transfers(all_trades: &mut SortedTrades, config: &Config) -> TransferReport {
+    let window = Duration::hours(config.transfers.time_window_hours);
+    let mut report = TransferReport::default();
+
+    for (asset, trades) in all_trades.iter_mut() {
+        let withdrawals = transfers_of_type(trades, TxnType::Withdrawal);
+        let mut deposits: Vec<Option<usize>> = transfers_of_type(trades, TxnType::Deposit).into_iter().map(Some).collect();
+        let mut network_fees: Vec<Trade> = vec![];
+
+        for w in withdrawals {
+            let pair = deposits.iter().position(|deposit| deposit.is_some_and(|d| is_pair(&trades[w], &trades[d], window, config.transfers.amount_tolerance)));
+
+            let Some(d) = pair.and_then(|index| deposits[index].take()) else {
+                report.unmatched.push(unmatched(asset, &trades[w], TransferDirection::Withdrawal));
+                continue;
+            };
+            let (withdrawal, deposit) = (&trades[w], &trades[d]);
+
+            let network_fee = withdrawal.base_asset_amount - deposit.base_asset_amount;
+            let transfer = MatchedTransfer {
+                asset: asset.to_owned(),
+                withdrawal_txn_id: withdrawal.txn_id.to_owned(),
+                deposit_txn_id: deposit.txn_id.to_owned(),
+                from_venue: withdrawal.venue.to_owned(),
+                to_venue: deposit.venue.to_owned(),
+                withdrawn_at: withdrawal.trade_time,
+                deposited_at: deposit.trade_time,
+                amount: deposit.base_asset_amount,
+                network_fee,
+            };
+
+            match (network_fee.is_zero(), withdrawal.price.is_zero()) {
+                (true, _) => {}
+                (false, false) => network_fees.push(network_fee_disposal(withdrawal, network_fee)),
+                (false, true) => report.unvalued_fees.push(transfer.clone()),
+            }
+            report.matched.push(transfer);
+
+            trades[w].transfer = Some(TransferLink { to_venue: trades[d].venue.to_owned(), amount: trades[d].base_asset_amount });
+        }
+
+        report.unmatched.extend(deposits.into_iter().flatten().map(|d| unmatched(asset, &trades[d], TransferDirection::Deposit)));
+        trades.extend(network_fees);
+    }
+
+    report.matched.sort_by(|a, b| (a.withdrawn_at, &a.asset).cmp(&(b.withdrawn_at, &b.asset)));
+    report.unmatched.sort_by(|a, b| (a.date, &a.asset).cmp(&(b.date, &b.asset)));
+    report.unvalued_fees.sort_by(|a, b| (a.withdrawn_at, &a.asset).cmp(&(b.withdrawn_at, &b.asset)));
+    report
+}
+
+
+/// Indices of the transfers of one direction, chronologically
+fn transfers_of_type(trades: &[Trade], txn_type: TxnType) -> Vec<usize> {
+    let mut transfers: Vec<usize> = (0..trades.len()).filter(|&i| trades[i].txn_type == txn_type).collect();
+    transfers.sort_by_key(|&i| trades[i].trade_time);
+    transfers
+}
+
+
+fn is_pair(withdrawal: &Trade, deposit: &Trade, window: Duration, tolerance: Decimal) -> bool {
+    let in_window = deposit.trade_time >= withdrawal.trade_time && deposit.trade_time - withdrawal.trade_time <= window;
+    let network_fee = withdrawal.base_asset_amount - deposit.base_asset_amount;
+    let in_tolerance = !network_fee.is_sign_negative() && network_fee <= withdrawal.base_asset_amount * tolerance;
+    let different_venues = match (&withdrawal.venue, &deposit.venue) {
+        (Some(from), Some(to)) => from != to,
+        _ => true,
+    };
+
+    in_window && in_tolerance && different_venues
+}
+
+
+/// The amount lost in transit, as a sale at the withdrawal's value
+fn network_fee_disposal(withdrawal: &Trade, network_fee: Decimal) -> Trade {
+    let mut disposal = Trade::synthetic_lot(
+        &withdrawal.base_asset,
+        &withdrawal.quote_asset,
+        network_fee,
+        withdrawal.price * network_fee,
+        withdrawal.trade_time,
+        withdrawal.txn_id.as_ref().map(|id| format!("{}-network-fee", id)),
+    );
+    disposal.txn_type = TxnType::Sale;
+    disposal.venue = withdrawal.venue.to_owned();
+    disposal
+}
+
+
+fn unmatched(asset: &str, transfer: &Trade, direction: TransferDirection) -> UnmatchedTransfer {
+    UnmatchedTransfer {
+        asset: asset.to_string(),
+        txn_id: transfer.txn_id.to_owned(),
+        venue: transfer.venue.to_owned(),
+        date: transfer.trade_time,
+        direction,
+        amount: transfer.base_asset_amount,
+    }
+}
@@ -1,32 +1,205 @@
+use regex::Regex;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
 
-/// All trades are classified as Buy, Sale, or Other, where Other can includes ignored, non-capital gains events (eg transfer)
+use crate::funcs::config::Config;
+use crate::funcs::import_trades::{CsvRecord, SortedTrades};
+
+
+/// All trades are classified as Buy, Sale, Income, Deposit, Withdrawal or Other, where Other can includes ignored, non-capital gains events
 #[derive(Debug, Clone, PartialEq)]
 pub enum TxnType {
     Buy,
     Sale,
+    Income(IncomeKind), // Received as ordinary income. Creates a lot at fair market value like a buy
+    Deposit, // Transfer in, paired with a withdrawal by `transfers::match_transfers`
+    Withdrawal, // Transfer out
     Other,
 }
 
 impl TxnType {
-    /// Returns txn_type enum for provided txn_type string and available classification vectors
-    pub fn return_txn_type(
-        match_string: &String,
-        buy_vector: &[String],
-        sell_vector: &[String],
-    ) -> TxnType {
-        match match_string {
-            _ if contains_in_vector(&match_string, buy_vector) => TxnType::Buy, //Question: Why does analyzer recommend I remove the & in the &match_string?
-            _ if contains_in_vector(&match_string, sell_vector) => TxnType::Sale,
-            _ => TxnType::Other,
+    /// Classifies a csv row. The `[txn_rule.<name>]` rules are tried in precedence order, then the
+    /// `*_txn_types` lists, whose entries match the whole type ignoring case: buys, sells, income,
+    /// deposits, then withdrawals. None when nothing matched, which is treated as Other
+    pub fn classify(record: &CsvRecord, config: &Config) -> Option<TxnType> {
+        if let Some(rule) = config.txn_rules.iter().find(|rule| rule.matches(record)) {
+            return Some(rule.txn_type.to_owned());
+        }
+
+        let raw_type = record.txn_type.trim();
+        let listed_in = |list: &[String]| list.iter().any(|entry| raw_type.eq_ignore_ascii_case(entry.trim()));
+
+        let income_kind = config.income_txn_types
+            .iter()
+            .find(|(_, list)| listed_in(list))
+            .map(|(kind, _)| *kind);
+
+        match income_kind {
+            _ if listed_in(&config.buy_txn_types) => Some(TxnType::Buy),
+            _ if listed_in(&config.sell_txn_types) => Some(TxnType::Sale),
+            Some(kind) => Some(TxnType::Income(kind)),
+            _ if listed_in(&config.transfers.deposit_txn_types) => Some(TxnType::Deposit),
+            _ if listed_in(&config.transfers.withdrawal_txn_types) => Some(TxnType::Withdrawal),
+            None => None,
+        }
+    }
+
+    /// Matches the `txn_type` of a `[txn_rule.<name>]` section: buy, sell, deposit, withdrawal, other or an income kind
+    pub fn match_rule_txn_type(txn_type: &str) -> Option<Self> {
+        match txn_type.to_lowercase().as_str() {
+            "buy" => Some(TxnType::Buy),
+            "sell" | "sale" => Some(TxnType::Sale),
+            "deposit" => Some(TxnType::Deposit),
+            "withdrawal" => Some(TxnType::Withdrawal),
+            "other" => Some(TxnType::Other),
+            kind => IncomeKind::match_income_kind(kind).map(TxnType::Income),
+        }
+    }
+
+    /// Buys and income both create lots that sales are matched against
+    pub fn is_acquisition(&self) -> bool {
+        matches!(self, TxnType::Buy | TxnType::Income(_))
+    }
+}
+
+
+/// A configured classification rule. It applies when the pattern matches the row's type and every set condition holds
+#[derive(Debug, Clone)]
+pub struct TxnRule {
+    pub name: String,
+    pub txn_type: TxnType,
+    pub pattern: TxnPattern,
+    pub assets: Vec<String>, // Base assets the rule is limited to, case-insensitive. Empty for any asset
+    pub notes: Option<Regex>, // Must match the row's notes
+    pub amount_sign: Option<AmountSign>, // Of the base asset amount
+    pub precedence: i64, // Lower is tried first. Equal precedence keeps config order
+}
+
+impl TxnRule {
+    pub fn matches(&self, record: &CsvRecord) -> bool {
+        let asset_matches = self.assets.is_empty() || self.assets.iter().any(|asset| asset.eq_ignore_ascii_case(record.base_asset.trim()));
+        let notes_match = self.notes.as_ref().is_none_or(|notes| notes.is_match(record.notes.as_deref().unwrap_or_default()));
+        let sign_matches = self.amount_sign.is_none_or(|sign| sign.matches(&record.base_asset_amount));
+
+        self.pattern.matches(record.txn_type.trim()) && asset_matches && notes_match && sign_matches
+    }
+}
+
+
+/// How a rule's pattern is compared with the raw type string
+#[derive(Debug, Clone)]
+pub enum TxnPattern {
+    Exact(String),
+    CaseInsensitive(String),
+    Prefix(String),
+    Contains(String),
+    Regex(Regex),
+}
+
+impl TxnPattern {
+    /// Builds the pattern for a `match` kind: exact, case_insensitive, prefix, contains or regex
+    pub fn new(match_kind: &str, pattern: &str) -> Option<Self> {
+        match match_kind.to_lowercase().as_str() {
+            "exact" => Some(TxnPattern::Exact(pattern.to_string())),
+            "case_insensitive" => Some(TxnPattern::CaseInsensitive(pattern.to_lowercase())),
+            "prefix" => Some(TxnPattern::Prefix(pattern.to_string())),
+            "contains" => Some(TxnPattern::Contains(pattern.to_string())),
+            "regex" => Regex::new(pattern).ok().map(TxnPattern::Regex),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, raw_type: &str) -> bool {
+        match self {
+            TxnPattern::Exact(pattern) => raw_type == pattern,
+            TxnPattern::CaseInsensitive(pattern) => raw_type.to_lowercase() == *pattern,
+            TxnPattern::Prefix(pattern) => raw_type.starts_with(pattern.as_str()),
+            TxnPattern::Contains(pattern) => raw_type.contains(pattern.as_str()),
+            TxnPattern::Regex(regex) => regex.is_match(raw_type),
         }
     }
 }
 
 
-/// Returns bool of input string contained in any string in vector of strings
-pub fn contains_in_vector(string: &str, string_vector: &[String]) -> bool {
-    string_vector.
-    iter().
-    any(|s| string.contains(s))
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmountSign {
+    Positive,
+    Negative,
+}
+
+impl AmountSign {
+    pub fn match_amount_sign(sign: &str) -> Option<Self> {
+        match sign.to_lowercase().as_str() {
+            "positive" => Some(AmountSign::Positive),
+            "negative" => Some(AmountSign::Negative),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, amount: &str) -> bool {
+        match amount.trim().parse::<Decimal>() {
+            Ok(amount) if amount.is_zero() => false,
+            Ok(amount) => amount.is_sign_negative() == (*self == AmountSign::Negative),
+            Err(_) => false,
+        }
+    }
+}
+
+
+/// A raw type string that no rule or list classified, with the number of trades it appeared on
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnclassifiedTxnType {
+    pub txn_type: String,
+    pub trades: usize,
+}
+
+/// Every raw type string that fell through to Other, alphabetically. Types a rule sets to Other aren't included
+pub fn get_unclassified_txn_types(all_trades: &SortedTrades) -> Vec<UnclassifiedTxnType> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for raw_type in all_trades.values().flatten().filter_map(|trade| trade.unclassified_txn_type.as_deref()) {
+        *counts.entry(raw_type).or_default() += 1;
+    }
+
+    counts
+        .into_iter()
+        .map(|(txn_type, trades)| UnclassifiedTxnType { txn_type: txn_type.to_string(), trades })
+        .collect()
+}
+
+
+/// Sub-category of an income transaction, for the ordinary-income report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum IncomeKind {
+    Staking,
+    Mining,
+    Airdrop,
+    Interest,
+    Referral,
 }
 
+impl IncomeKind {
+    /// Matches an `[income_txn_types]` key to an `IncomeKind`
+    pub fn match_income_kind(kind: &str) -> Option<Self> {
+        match kind.to_lowercase().as_str() {
+            "staking" => Some(Self::Staking),
+            "mining" => Some(Self::Mining),
+            "airdrop" => Some(Self::Airdrop),
+            "interest" => Some(Self::Interest),
+            "referral" => Some(Self::Referral),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for IncomeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IncomeKind::Staking => write!(f, "Staking"),
+            IncomeKind::Mining => write!(f, "Mining"),
+            IncomeKind::Airdrop => write!(f, "Airdrop"),
+            IncomeKind::Interest => write!(f, "Interest"),
+            IncomeKind::Referral => write!(f, "Referral"),
+        }
+    }
+}
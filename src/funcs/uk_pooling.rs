@@ -0,0 +1,175 @@
+//! HMRC share identification rules. Disposals are matched, in order, against:
+//! 1. Acquisitions on the same day
+//! 2. Acquisitions in the following 30 days ("bed and breakfast")
+//! 3. The Section 104 pool of everything else, at its average cost
+
+use chrono::{Datelike, Days, NaiveDate};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::cmp;
+
+use crate::funcs::config::Config;
+use crate::funcs::inventory::{opening_balance_lot, resolve_shortfall, InventoryError, Shortfall};
+use crate::funcs::process_trades::{MatchRule, SaleEvent, DUST_THRESHOLD};
+use crate::funcs::trade::Trade;
+use crate::funcs::txn_type::TxnType;
+
+
+/// State of an asset's Section 104 pool at a tax-year end (5 April)
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolSnapshot {
+    pub asset: String,
+    pub tax_year_end: NaiveDate,
+    pub amount: Decimal,
+    pub cost_basis: Decimal,
+}
+
+
+/// Orders the snapshots of all assets by tax year, then asset
+pub fn sort_pool_snapshots(snapshots: &mut [PoolSnapshot]) {
+    snapshots.sort_by(|a, b| (a.tax_year_end, &a.asset).cmp(&(b.tax_year_end, &b.asset)));
+}
+
+
+/// Matches one asset's disposals using the UK rules.
+/// Returns the remaining Section 104 pool as a single lot, and the pool state at each tax-year end
+pub fn match_uk_pooling(
+    trades: &[Trade],
+    config: &Config,
+    sale_events: &mut Vec<SaleEvent>,
+    shortfalls: &mut Vec<Shortfall>,
+) -> Result<(Vec<Trade>, Vec<PoolSnapshot>), InventoryError> {
+    let mut opening_lot = trades.first().and_then(|trade| opening_balance_lot(config, &trade.base_asset, &trade.quote_asset));
+    let long_term_months = config.holding_period.long_term_months;
+
+    // HMRC treats all acquisitions (and disposals) on one day as a single transaction
+    let mut acquisitions = aggregate_by_day(trades, |txn_type| txn_type.is_acquisition());
+    let mut disposals = aggregate_by_day(trades, |txn_type| *txn_type == TxnType::Sale);
+
+    // 1. Same day
+    for disposal in disposals.iter_mut() {
+        let day = disposal.trade_time.date();
+        if let Some(acquisition) = acquisitions.iter_mut().find(|acquisition| acquisition.trade_time.date() == day) {
+            match_clip(acquisition, disposal, MatchRule::SameDay, long_term_months, sale_events);
+        }
+    }
+
+    // 2. Bed and breakfast: earliest acquisition in the next 30 days first, earlier disposals first
+    for disposal in disposals.iter_mut() {
+        let day = disposal.trade_time.date();
+        let window_end = day + Days::new(30);
+
+        for acquisition in acquisitions.iter_mut() {
+            let acquired = acquisition.trade_time.date();
+            if acquired <= day || acquired > window_end || acquisition.remaining < DUST_THRESHOLD {
+                continue;
+            }
+
+            match_clip(acquisition, disposal, MatchRule::BedAndBreakfast, long_term_months, sale_events);
+
+            if disposal.remaining < DUST_THRESHOLD {
+                break;
+            }
+        }
+    }
+
+    // 3. Section 104 pool, chronologically with acquisitions first on a shared day
+    let mut txn_list: Vec<Trade> = acquisitions
+        .into_iter()
+        .chain(disposals)
+        .filter(|txn| txn.remaining >= DUST_THRESHOLD)
+        .collect();
+    txn_list.sort_by_key(|k| (k.trade_time.date(), !k.txn_type.is_acquisition()));
+
+    let asset = trades.first().map(|trade| trade.base_asset.to_owned()).unwrap_or_default();
+    let mut snapshots: Vec<PoolSnapshot> = vec![];
+    let mut next_year_end: Option<NaiveDate> = txn_list.first().map(|txn| tax_year_end(txn.trade_time.date()));
+    let mut pool: Option<Trade> = None;
+
+    for mut txn in txn_list {
+        while let Some(year_end) = next_year_end.filter(|year_end| txn.trade_time.date() > *year_end) {
+            snapshots.push(snapshot(&asset, year_end, pool.as_ref()));
+            next_year_end = Some(tax_year_end(year_end + Days::new(1)));
+        }
+
+        match (txn.txn_type.clone(), pool.as_mut()) {
+            (TxnType::Buy | TxnType::Income(_), Some(pooled)) if pooled.remaining >= DUST_THRESHOLD => pooled.add_to_pool(&txn),
+            (TxnType::Buy | TxnType::Income(_), _) => pool = Some(txn.as_pool()),
+            (TxnType::Sale, Some(pooled)) if pooled.remaining >= DUST_THRESHOLD => {
+                let clip_size = cmp::min(pooled.remaining, txn.remaining);
+                let mut event = SaleEvent::new(pooled, &txn, clip_size, long_term_months);
+                event.match_rule = MatchRule::Section104;
+
+                sale_events.push(event);
+
+                pooled.remove_from_pool(clip_size);
+                txn.remaining -= clip_size;
+            }
+            _ => {}
+        }
+
+        if txn.txn_type == TxnType::Sale {
+            resolve_shortfall(&mut txn, config, opening_lot.as_mut(), sale_events, shortfalls)?;
+        }
+    }
+
+    if let Some(year_end) = next_year_end {
+        snapshots.push(snapshot(&asset, year_end, pool.as_ref()));
+    }
+
+    let remaining_pool = pool.into_iter().chain(opening_lot).filter(|pooled| pooled.remaining >= DUST_THRESHOLD).collect();
+    Ok((remaining_pool, snapshots))
+}
+
+
+/// Matches as much as possible of the disposal against the acquisition
+fn match_clip(acquisition: &mut Trade, disposal: &mut Trade, rule: MatchRule, long_term_months: u32, sale_events: &mut Vec<SaleEvent>) {
+    let clip_size = cmp::min(acquisition.remaining, disposal.remaining);
+    if clip_size.is_zero() {
+        return;
+    }
+
+    let mut event = SaleEvent::new(acquisition, disposal, clip_size, long_term_months);
+    event.match_rule = rule;
+    sale_events.push(event);
+
+    acquisition.remaining -= clip_size;
+    disposal.remaining -= clip_size;
+}
+
+
+/// Combines all trades of the selected types on the same calendar day into a single trade, sorted by day
+fn aggregate_by_day(trades: &[Trade], include: impl Fn(&TxnType) -> bool) -> Vec<Trade> {
+    let mut filtered: Vec<&Trade> = trades.iter().filter(|trade| include(&trade.txn_type)).collect();
+    filtered.sort_by_key(|k| k.trade_time);
+
+    let mut aggregated: Vec<Trade> = vec![];
+    for trade in filtered {
+        match aggregated.last_mut() {
+            Some(last) if last.trade_time.date() == trade.trade_time.date() => last.add_to_pool(trade),
+            _ => aggregated.push(trade.as_pool()),
+        }
+    }
+    aggregated
+}
+
+
+/// The 5 April that ends the UK tax year containing `date`
+fn tax_year_end(date: NaiveDate) -> NaiveDate {
+    let this_year_end = NaiveDate::from_ymd_opt(date.year(), 4, 5).unwrap();
+    if date <= this_year_end {
+        this_year_end
+    } else {
+        NaiveDate::from_ymd_opt(date.year() + 1, 4, 5).unwrap()
+    }
+}
+
+
+fn snapshot(asset: &str, tax_year_end: NaiveDate, pool: Option<&Trade>) -> PoolSnapshot {
+    PoolSnapshot {
+        asset: asset.to_string(),
+        tax_year_end,
+        amount: pool.map(|pooled| pooled.remaining).unwrap_or_default(),
+        cost_basis: pool.map(|pooled| pooled.quote_asset_amount + pooled.fee).unwrap_or_default(),
+    }
+}
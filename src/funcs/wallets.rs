@@ -0,0 +1,114 @@
+//! Per-venue lot tracking. Each venue (exchange account or wallet) holds its own lots, sales only match lots
+//! held on the same venue, and paired transfers carry lots across with their original basis and acquisition date
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::funcs::config::{AccountingType, Config};
+use crate::funcs::error::CryptotaxError;
+use crate::funcs::inventory::{opening_balance_lot, resolve_shortfall, Shortfall};
+use crate::funcs::lot_selection::order_lots;
+use crate::funcs::process_trades::{consume_lots, sort_lots, SaleEvent, DUST_THRESHOLD};
+use crate::funcs::specific_id::{apply_assignments, LotAssignment};
+use crate::funcs::trade::Trade;
+use crate::funcs::txn_type::TxnType;
+
+
+/// Matches one asset's sales against the lots held on the sale's venue, replaying acquisitions, sales and
+/// paired withdrawals chronologically. Returns the lots with a remaining balance across all venues
+pub fn match_lots_by_venue(
+    trades: &[Trade],
+    config: &Config,
+    acct_type: &AccountingType,
+    assignments: &HashMap<String, Vec<LotAssignment>>,
+    sale_events: &mut Vec<SaleEvent>,
+    shortfalls: &mut Vec<Shortfall>,
+) -> Result<Vec<Trade>, CryptotaxError> {
+    let mut opening_lot = trades.first().and_then(|trade| opening_balance_lot(config, &trade.base_asset, &trade.quote_asset));
+    let mut wallets: BTreeMap<String, Vec<Trade>> = BTreeMap::new();
+
+    // The configured order, as without venues. Only events it can't separate, eg a network fee and its
+    // withdrawal, fall back to acquisitions, then sales, then transfers out. Matched deposits are covered
+    // by their withdrawal, unmatched ones have no known basis and are left for review
+    let mut events: Vec<Trade> = trades
+        .iter()
+        .filter(|trade| trade.txn_type.is_acquisition() || trade.txn_type == TxnType::Sale || trade.transfer.is_some())
+        .cloned()
+        .collect();
+    events.sort_by(|a, b| a.cmp_chronological(b, config.tie_breaker).then(event_rank(a).cmp(&event_rank(b))));
+
+    for mut event in events {
+        let wallet = wallets.entry(event.venue.to_owned().unwrap_or_default()).or_default();
+
+        match event.txn_type {
+            TxnType::Sale => {
+                if let Some(assigned) = event.txn_id.as_ref().and_then(|id| assignments.get(id)) {
+                    apply_assignments(&mut event, wallet, assigned, config.holding_period.long_term_months, sale_events)?;
+                }
+                if event.remaining >= DUST_THRESHOLD {
+                    consume_lots(wallet, &mut event, acct_type, config, sale_events);
+                }
+                resolve_shortfall(&mut event, config, opening_lot.as_mut(), sale_events, shortfalls)?;
+            }
+            TxnType::Withdrawal => {
+                let moved = move_lots(wallet, &event, acct_type, config, shortfalls);
+                let to_venue = event.transfer.and_then(|link| link.to_venue).unwrap_or_default();
+
+                let destination = wallets.entry(to_venue).or_default();
+                destination.extend(moved);
+                sort_lots(destination, acct_type, config.tie_breaker);
+            }
+            _ => {
+                wallet.push(event);
+                sort_lots(wallet, acct_type, config.tie_breaker);
+            }
+        }
+    }
+
+    Ok(wallets
+        .into_values()
+        .flatten()
+        .chain(opening_lot)
+        .filter(|lot| lot.remaining >= DUST_THRESHOLD)
+        .collect())
+}
+
+
+fn event_rank(trade: &Trade) -> u8 {
+    match trade.txn_type {
+        _ if trade.txn_type.is_acquisition() => 0,
+        TxnType::Sale => 1,
+        _ => 2,
+    }
+}
+
+
+/// Takes the amount received by a paired withdrawal out of the wallet, in the order the accounting type
+/// would sell it. Any amount the wallet doesn't hold is recorded as a shortfall
+fn move_lots(wallet: &mut [Trade], withdrawal: &Trade, acct_type: &AccountingType, config: &Config, shortfalls: &mut Vec<Shortfall>) -> Vec<Trade> {
+    let mut to_move = withdrawal.transfer.as_ref().map(|link| link.amount).unwrap_or_default();
+    let mut moved: Vec<Trade> = vec![];
+
+    for i in order_lots(wallet, withdrawal, acct_type, config) {
+        if to_move < DUST_THRESHOLD {
+            break;
+        }
+        let lot = &mut wallet[i];
+        let clip_size = lot.remaining.min(to_move);
+
+        let mut moved_lot = lot.split_off(clip_size);
+        moved_lot.venue = withdrawal.transfer.as_ref().and_then(|link| link.to_venue.to_owned());
+        moved.push(moved_lot);
+
+        to_move -= clip_size;
+    }
+
+    if to_move >= DUST_THRESHOLD {
+        shortfalls.push(Shortfall {
+            asset: withdrawal.base_asset.to_owned(),
+            sale_date: withdrawal.trade_time,
+            txn_id: withdrawal.txn_id.to_owned(),
+            unmatched_amount: to_move,
+        });
+    }
+    moved
+}
@@ -1 +1,12 @@
-pub mod funcs;
\ No newline at end of file
+//! Processes a csv of crypto trades into gain/loss events using LIFO, FIFO, HIFO, LOFO, HTFO, tax-minimising, average cost (ACB), UK share pooling or specific identification.
+//!
+//! `TaxEngine` is the entry point for embedding the engine in other tools. The `funcs` modules
+//! remain public for callers that need the individual import / matching steps.
+
+pub mod funcs;
+
+pub use funcs::config::{build_config, AccountingType, Config, ConfigParseError};
+pub use funcs::engine::{TaxEngine, TaxReport};
+pub use funcs::error::CryptotaxError;
+pub use funcs::process_trades::SaleEvent;
+pub use funcs::trade::Trade;
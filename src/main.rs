@@ -1,39 +1,97 @@
-mod funcs;
 use std::env;
 use std::error::Error;
 use colored::Colorize;
-use funcs::process_trades::SaleEvent;
+use cryptotax::funcs::income::IncomeEvent;
+use cryptotax::funcs::open_lots::OpenLot;
+use cryptotax::funcs::trade::parse_datetime_string;
+use cryptotax::{CryptotaxError, SaleEvent, TaxEngine};
 use std::path::PathBuf;
 use polars::prelude::*;
 use std::fs::File;
+use serde::Serialize;
 
 
 
-#[allow(warnings, dead_code)]
-
 /// Given filepath to config file
 fn main() -> Result<(), Box<dyn Error>> {
 
     // Config
     let config_filepath: PathBuf = collect_config_filepath()?;
-    let config = match funcs::config::build_config(config_filepath) {
-        Ok(config) => config,
-        Err(ConfigParseError) => panic!("{}", ConfigParseError.to_string().on_purple()),
+    let engine = match TaxEngine::from_config_file(config_filepath) {
+        Ok(engine) => engine,
+        Err(config_parse_error) => {
+            eprintln!("{}", config_parse_error.to_string().on_purple());
+            std::process::exit(1);
+        }
     };
 
-    // Import Trades
-    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    // Safe-harbor allocation: cryptotax <config> safe-harbor <cut-over date> <wallet balances csv>
+    if env::args().nth(2).as_deref() == Some("safe-harbor") {
+        return allocate_safe_harbor(&engine);
+    }
 
-    // Process Trades
-    let (sale_events, cost_bases) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config);
-    let mut annual_summary = funcs::process_trades::get_annual_summary(&sale_events);
+    // Import & Process Trades
+    let report = match engine.run() {
+        Ok(report) => report,
+        Err(error) => {
+            if let CryptotaxError::InvalidRows(import_errors) = &error {
+                vec_to_csv(import_errors, "import_errors")?;
+            }
+            eprintln!("{}", error.to_string().on_purple());
+            std::process::exit(1);
+        }
+    };
     
     
     // Export
-    println!("{:?}", cost_bases);
-    println!("{}", annual_summary);
-    vec_to_csv(&sale_events, "sale_events");
-    df_to_csv(&annual_summary, "annual_summary")?;
+    if !report.import_errors.is_empty() {
+        println!("{}", format!("WARNING: Skipped {} rows that could not be imported, see import_errors.csv", report.import_errors.len()).yellow());
+        vec_to_csv(&report.import_errors, "import_errors")?;
+    }
+    for shortfall in report.shortfalls.iter() {
+        println!("{}", format!("WARNING: {} sale on {} exceeded holdings by {}", shortfall.asset, shortfall.sale_date, shortfall.unmatched_amount).yellow());
+    }
+    println!("{:?}", report.cost_bases);
+    println!("{}", report.annual_summary);
+    let rounded_sale_events: Vec<SaleEvent> = report.sale_events
+        .iter()
+        .map(|sale_event| sale_event.rounded(&engine.config().rounding))
+        .collect();
+    vec_to_csv(&rounded_sale_events, "sale_events")?;
+    df_to_csv(&report.annual_summary, "annual_summary")?;
+    let rounded_open_lots: Vec<OpenLot> = report.open_lot_report
+        .iter()
+        .map(|open_lot| open_lot.rounded(&engine.config().rounding))
+        .collect();
+    vec_to_csv(&rounded_open_lots, "open_lots")?;
+    vec_to_json(&rounded_open_lots, "open_lots")?;
+    if !report.income_events.is_empty() {
+        println!("{}", report.income_summary);
+        let rounded_income_events: Vec<IncomeEvent> = report.income_events
+            .iter()
+            .map(|income_event| income_event.rounded(&engine.config().rounding))
+            .collect();
+        vec_to_csv(&rounded_income_events, "income_events")?;
+        df_to_csv(&report.income_summary, "income_summary")?;
+    }
+    if !report.transfers.matched.is_empty() {
+        vec_to_csv(&report.transfers.matched, "transfers")?;
+    }
+    if !report.transfers.unmatched.is_empty() {
+        println!("{}", format!("WARNING: {} transfers could not be paired, see unmatched_transfers.csv", report.transfers.unmatched.len()).yellow());
+        vec_to_csv(&report.transfers.unmatched, "unmatched_transfers")?;
+    }
+    if !report.transfers.unvalued_fees.is_empty() {
+        println!("{}", format!("WARNING: {} transfer network fees had no value and were not disposed of, see unvalued_network_fees.csv", report.transfers.unvalued_fees.len()).yellow());
+        vec_to_csv(&report.transfers.unvalued_fees, "unvalued_network_fees")?;
+    }
+    if !report.unclassified_txn_types.is_empty() {
+        println!("{}", format!("WARNING: {} txn types were classified as Other, see unclassified_txn_types.csv", report.unclassified_txn_types.len()).yellow());
+        vec_to_csv(&report.unclassified_txn_types, "unclassified_txn_types")?;
+    }
+    if !report.pool_snapshots.is_empty() {
+        vec_to_csv(&report.pool_snapshots, "pool_snapshots")?;
+    }
 
     Ok(())
 }
@@ -46,15 +104,39 @@ fn collect_config_filepath() -> Result<PathBuf, String> {
     }
 }
 
+fn allocate_safe_harbor(engine: &TaxEngine) -> Result<(), Box<dyn Error>> {
+    let (Some(cutover), Some(balances_filepath)) = (env::args().nth(3), env::args().nth(4)) else {
+        return Err("Please provide the cut-over date and the wallet balances csv after safe-harbor".into());
+    };
+    let cutover = parse_datetime_string(&cutover).map_err(|e| format!("Invalid cut-over date: {}", e))?;
+
+    match engine.safe_harbor_allocation(cutover, &PathBuf::from(balances_filepath)) {
+        Ok(allocation) => vec_to_csv(&allocation, "safe_harbor_allocation"),
+        Err(error) => {
+            eprintln!("{}", error.to_string().on_purple());
+            std::process::exit(1);
+        }
+    }
+}
+
 fn df_to_csv(df: &DataFrame, csv_name: &str) -> Result<(), Box<dyn Error>> {
     let mut df = df.clone();
+
+    // The csv writer can't serialize Decimal columns, so write their exact string form
+    for name in df.get_column_names_owned() {
+        if let DataType::Decimal(_, _) = df.column(&name)?.dtype() {
+            let as_string = df.column(&name)?.cast(&DataType::String)?;
+            df.with_column(as_string)?;
+        }
+    }
+
     let output_file: File = File::create(format!("{}.csv",csv_name))?;
     let mut writer: CsvWriter<File> = CsvWriter::new(output_file).include_header(true);
     writer.finish(&mut df)?;
     Ok(())
 }
 
-fn vec_to_csv(vec: &[SaleEvent], csv_name: &str) -> Result<(), Box<dyn Error>> {
+fn vec_to_csv<T: Serialize + Clone>(vec: &[T], csv_name: &str) -> Result<(), Box<dyn Error>> {
     let vec = vec.to_owned();
     let mut writer = csv::Writer::from_path(format!("{}.csv",csv_name))?;
     for row in &vec {
@@ -64,6 +146,12 @@ fn vec_to_csv(vec: &[SaleEvent], csv_name: &str) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn vec_to_json<T: Serialize>(vec: &[T], json_name: &str) -> Result<(), Box<dyn Error>> {
+    let output_file: File = File::create(format!("{}.json", json_name))?;
+    serde_json::to_writer_pretty(output_file, vec)?;
+    Ok(())
+}
+
 
 // OLD CODE
 
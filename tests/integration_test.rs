@@ -1,7 +1,19 @@
 use std::path::PathBuf;
 
 use cryptotax::funcs;
-use cryptotax::funcs::config::AccountingType;
+use cryptotax::CryptotaxError;
+use polars::prelude::DataType;
+use rust_decimal::Decimal;
+use cryptotax::funcs::config::{AccountingType, ConfigParseError, FileFormat, ImportErrorPolicy, OpeningBalance, SafeHarborConfig, ShortfallPolicy, SwapValuation, TieBreaker};
+use cryptotax::funcs::inventory::InventoryError;
+use cryptotax::funcs::holding_period::HoldingPeriod;
+use cryptotax::funcs::price_source::PriceError;
+use cryptotax::funcs::process_trades::MatchRule;
+use cryptotax::funcs::safe_harbor::SafeHarborError;
+use cryptotax::funcs::swaps::SwapError;
+use cryptotax::funcs::transfers::TransferDirection;
+use cryptotax::funcs::txn_type::{AmountSign, IncomeKind, TxnPattern, TxnRule, TxnType};
+use cryptotax::funcs::specific_id::LotAssignmentError;
 
 #[test]
 fn integration_test() {
@@ -11,16 +23,1023 @@ fn integration_test() {
 
     assert!(matches!(config.accounting_type, AccountingType::FIFO | AccountingType::LIFO | AccountingType::HIFO));
     
-    for i in [AccountingType::FIFO, AccountingType::LIFO, AccountingType::HIFO].iter() {
+    for i in [AccountingType::FIFO, AccountingType::LIFO, AccountingType::HIFO, AccountingType::LOFO, AccountingType::HTFO, AccountingType::MinTax, AccountingType::ACB, AccountingType::UkPooling].iter() {
         config.accounting_type = i.clone();
         let trades = funcs::import_trades::import_trades(&config).unwrap();
-        let (sale_events, _) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config);
-        let gain_loss_summary: f32 = sale_events.iter().map(|sale_event| sale_event.gain_loss).sum();
+        let (sale_events, _) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config).unwrap();
+        let gain_loss_summary: Decimal = sale_events.iter().map(|sale_event| sale_event.gain_loss).sum();
+        let gain_loss_summary = config.rounding.apply(gain_loss_summary);
 
         match config.accounting_type {
-            AccountingType::FIFO => assert_eq!(format!("{:.2}", gain_loss_summary), format!("{:.2}", -2577.7231)),
-            AccountingType::LIFO => assert_eq!(format!("{:.2}", gain_loss_summary), format!("{:.2}", -5249.9736)),
-            AccountingType::HIFO => assert_eq!(format!("{:.2}", gain_loss_summary), format!("{:.2}", -5754.5923)),
+            AccountingType::FIFO => assert_eq!(gain_loss_summary, Decimal::new(-257772, 2)),
+            AccountingType::LIFO => assert_eq!(gain_loss_summary, Decimal::new(-524997, 2)),
+            AccountingType::HIFO => assert_eq!(gain_loss_summary, Decimal::new(-575459, 2)),
+            AccountingType::LOFO => assert_eq!(gain_loss_summary, Decimal::new(-14094, 2)),
+            AccountingType::HTFO => assert_eq!(gain_loss_summary, Decimal::new(-575459, 2)),
+            AccountingType::MinTax => assert_eq!(gain_loss_summary, Decimal::new(-575459, 2)),
+            AccountingType::ACB => assert_eq!(gain_loss_summary, Decimal::new(-305687, 2)),
+            AccountingType::UkPooling => assert_eq!(gain_loss_summary, Decimal::new(-305687, 2)), // No same-day or 30-day matches in this data, so same as ACB
+            AccountingType::SpecificId => unreachable!(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn engine_test() {
+
+    let engine = cryptotax::TaxEngine::from_config_file(PathBuf::from("tests/test_config.ini")).unwrap();
+    let report = engine.run().unwrap();
+
+    assert!(!report.sale_events.is_empty());
+    assert_eq!(report.annual_summary.get_column_names()[0], "Asset Name");
+
+    // NFT_A was bought and sold in full, NFT_B is still held
+    assert!(report.open_lots["NFT_A"].is_empty());
+    assert_eq!(report.open_lots["NFT_B"].len(), 1);
+
+    // An asset with no trades is skipped rather than panicking, whatever the method
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    for (accounting_type, venues) in [(AccountingType::FIFO, None), (AccountingType::FIFO, Some(vec![])), (AccountingType::ACB, None), (AccountingType::UkPooling, None)] {
+        (config.accounting_type, config.venues) = (accounting_type, venues);
+        let empty: funcs::import_trades::SortedTrades = [("BTC".to_string(), vec![])].into_iter().collect();
+        let report = cryptotax::TaxEngine::new(config.clone()).process(empty).unwrap();
+        assert!(report.sale_events.is_empty());
+    }
+}
+
+
+#[test]
+fn annual_summary_rounding_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let (sale_events, _) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config).unwrap();
+    let summary = funcs::process_trades::get_annual_summary(&sale_events, &config.rounding).unwrap();
+
+    // Each year column is an exact decimal with the configured number of places
+    for column in summary.get_columns().iter().skip(1) {
+        assert_eq!(column.dtype(), &DataType::Decimal(None, Some(2)));
+    }
+
+    let btc_2019 = summary.column("2019 Long-Term").unwrap().get(0).unwrap();
+    let expected = sale_events
+        .iter()
+        .filter(|sale| sale.name == "BTC" && sale.sell_year == 2019 && sale.holding_period == HoldingPeriod::LongTerm)
+        .map(|sale| sale.gain_loss)
+        .sum::<Decimal>();
+    assert_eq!(btc_2019.to_string(), config.rounding.apply(expected).to_string());
+}
+
+
+#[test]
+fn sale_event_allocation_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let (sale_events, _) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config).unwrap();
+
+    // 2020-05-08 BTC sale of 5.760324168 for 4292 is split across several buy lots
+    let btc_2020: Vec<_> = sale_events
+        .iter()
+        .filter(|sale| sale.name == "BTC" && sale.sell_year == 2020)
+        .collect();
+    assert!(btc_2020.len() > 1);
+
+    let proceeds: Decimal = btc_2020.iter().map(|sale| sale.proceeds).sum();
+    assert_eq!(config.rounding.apply(proceeds), Decimal::new(4292, 0));
+
+    for sale in sale_events.iter() {
+        assert_eq!(sale.gain_loss, sale.proceeds - sale.cost_basis);
+    }
+}
+
+
+#[test]
+fn holding_period_test() {
+
+    let dt = |s: &str| funcs::trade::parse_datetime_string(s).unwrap();
+
+    // Anniversary itself is still short-term, the day after is long-term
+    assert_eq!(HoldingPeriod::classify(dt("2021-03-15 00:00:00"), dt("2022-03-15 23:59:59"), 12), HoldingPeriod::ShortTerm);
+    assert_eq!(HoldingPeriod::classify(dt("2021-03-15 00:00:00"), dt("2022-03-16 00:00:00"), 12), HoldingPeriod::LongTerm);
+
+    // Leap day acquisition anniversaries on Feb 28
+    assert_eq!(HoldingPeriod::classify(dt("2020-02-29 00:00:00"), dt("2021-02-28 00:00:00"), 12), HoldingPeriod::ShortTerm);
+    assert_eq!(HoldingPeriod::classify(dt("2020-02-29 00:00:00"), dt("2021-03-01 00:00:00"), 12), HoldingPeriod::LongTerm);
+
+    // Holding across a leap day doesn't shift the anniversary
+    assert_eq!(HoldingPeriod::classify(dt("2023-03-01 00:00:00"), dt("2024-03-01 00:00:00"), 12), HoldingPeriod::ShortTerm);
+    assert_eq!(HoldingPeriod::classify(dt("2023-03-01 00:00:00"), dt("2024-03-02 00:00:00"), 12), HoldingPeriod::LongTerm);
+}
+
+
+#[test]
+fn fee_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/fee_transactions.csv");
+    for (column, field) in [("Fee", "fee_amount"), ("Fee Asset", "fee_asset"), ("Fee Value", "fee_value")] {
+        config.csv_columns.insert(column.to_string(), field.to_string());
+    }
+
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let (sale_events, _) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config).unwrap();
+
+    let find = |asset: &str, month: u32| {
+        sale_events
+            .iter()
+            .find(|sale| sale.name == asset && sale.sale_date.format("%m").to_string() == format!("{:02}", month))
+            .unwrap()
+    };
+
+    // Buy fee of 20 USD on 2 BTC adds 10 per unit to basis, BNB fee valued at 25 reduces proceeds
+    let march = find("BTC", 3);
+    assert_eq!(march.cost_basis, Decimal::new(15005, 0));
+    assert_eq!(march.proceeds, Decimal::new(24975, 0));
+    assert_eq!(march.fees, Decimal::new(30, 0));
+
+    // Paying the fee in BNB disposes of 0.1 BNB bought at 40
+    let bnb = find("BNB", 3);
+    assert_eq!(bnb.amount, Decimal::new(1, 1));
+    assert_eq!(bnb.gain_loss, Decimal::new(21, 0));
+
+    // Fee paid in BTC is valued at the trade price of 60000 and disposes of 0.0001 BTC
+    let april: Vec<_> = sale_events.iter().filter(|sale| sale.name == "BTC" && sale.sale_date.format("%m").to_string() == "04").collect();
+    assert_eq!(april.len(), 2);
+    assert!(april.iter().any(|sale| sale.proceeds == Decimal::new(29994, 0)));
+    assert!(april.iter().any(|sale| sale.amount == Decimal::new(1, 4) && sale.proceeds == Decimal::new(6, 0)));
+}
+
+
+#[test]
+fn average_cost_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::ACB;
+
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let (sale_events, cost_bases) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config).unwrap();
+
+    // ETH 2019-07-05 sale realises against the average of both 2018 buys: (2543.5 + 6484) / 10.19068642
+    let eth_sale = sale_events.iter().find(|sale| sale.name == "ETH").unwrap();
+    let pool_average = Decimal::new(90275, 1) / Decimal::new(1019068642, 8);
+    assert_eq!(config.rounding.apply(eth_sale.cost_basis), config.rounding.apply(pool_average * eth_sale.amount));
+    // The pool of both buys has no single txn id to point to
+    assert_eq!((eth_sale.buy_txn_id.as_str(), eth_sale.sale_txn_id.is_empty()), ("", false));
+
+    // All remaining ETH is reported as a single pool whose average is unchanged by the sale
+    let eth_pool_before_2019_buy = (Decimal::new(90275, 1), Decimal::new(1019068642, 8));
+    let remaining = eth_pool_before_2019_buy.1 - Decimal::new(84323536, 7);
+    let expected_pool = eth_pool_before_2019_buy.0 * remaining / eth_pool_before_2019_buy.1 + Decimal::new(6483, 0);
+    let expected_average = expected_pool / (remaining + Decimal::new(46, 0));
+    let eth_basis = cost_bases["ETH"].unwrap();
+    assert_eq!(config.rounding.apply(eth_basis * Decimal::new(1000, 0)), config.rounding.apply(expected_average * Decimal::new(1000, 0)));
+}
+
+
+#[test]
+fn uk_pooling_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::UkPooling;
+    config.filepath = PathBuf::from("tests/uk_transactions.csv");
+
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+
+    let by_rule = |rule: MatchRule| report.sale_events.iter().find(|sale| sale.match_rule == rule).unwrap();
+
+    // Same-day buy of 2 @ 280, then the 4 bought 19 days later @ 250, then 6 from the pool @ 150
+    let same_day = by_rule(MatchRule::SameDay);
+    assert_eq!((same_day.amount, same_day.cost_basis, same_day.gain_loss), (Decimal::new(2, 0), Decimal::new(560, 0), Decimal::new(40, 0)));
+
+    let bed_and_breakfast = by_rule(MatchRule::BedAndBreakfast);
+    assert_eq!((bed_and_breakfast.amount, bed_and_breakfast.cost_basis), (Decimal::new(4, 0), Decimal::new(1000, 0)));
+
+    let section_104 = by_rule(MatchRule::Section104);
+    assert_eq!((section_104.amount, section_104.cost_basis), (Decimal::new(6, 0), Decimal::new(900, 0)));
+
+    // Pool at 5 April 2021 and 2022
+    let snapshots: Vec<_> = report.pool_snapshots.iter().map(|s| (s.tax_year_end.to_string(), s.amount, s.cost_basis)).collect();
+    assert_eq!(snapshots, vec![
+        ("2021-04-05".to_string(), Decimal::new(10, 0), Decimal::new(1000, 0)),
+        ("2022-04-05".to_string(), Decimal::new(14, 0), Decimal::new(2100, 0)),
+    ]);
+}
+
+
+#[test]
+fn specific_id_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::SpecificId;
+    config.filepath = PathBuf::from("tests/specific_id_transactions.csv");
+    config.specific_id.filepath = PathBuf::from("tests/lot_assignments.csv");
+
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let (sale_events, _) = funcs::process_trades::get_sale_events_and_cost_basis(trades.clone(), &config).unwrap();
+
+    // 8 from b2 and 2 from b3 as assigned, then the unassigned 2 from b1 by FIFO
+    let matched: Vec<_> = sale_events.iter().map(|sale| (sale.match_rule, sale.amount, sale.cost_basis)).collect();
+    assert_eq!(matched, vec![
+        (MatchRule::SpecificId, Decimal::new(8, 0), Decimal::new(240, 0)),
+        (MatchRule::SpecificId, Decimal::new(2, 0), Decimal::new(40, 0)),
+        (MatchRule::Lot, Decimal::new(2, 0), Decimal::new(20, 0)),
+    ]);
+
+    // Assignments to a lot bought after the sale, or beyond what the lot holds, are errors
+    for (name, rows) in [("after_sale", "s1,b4,1"), ("exhausted", "s1,b2,11")] {
+        let filepath = std::env::temp_dir().join(format!("cryptotax_lot_assignments_{}.csv", name));
+        std::fs::write(&filepath, format!("sale_txn_id,buy_txn_id,amount\n{}\n", rows)).unwrap();
+        config.specific_id.filepath = filepath;
+
+        let error = funcs::process_trades::get_sale_events_and_cost_basis(trades.clone(), &config).unwrap_err();
+        match name {
+            "after_sale" => assert!(matches!(error, CryptotaxError::LotAssignment(LotAssignmentError::AcquiredAfterSale { .. }))),
+            _ => assert!(matches!(error, CryptotaxError::LotAssignment(LotAssignmentError::LotExhausted { .. }))),
+        }
+    }
+}
+
+
+#[test]
+fn lot_selection_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.filepath = PathBuf::from("tests/lot_selection_transactions.csv");
+
+    // Selling all 4 lots at 200 shows the order each method consumes them in
+    let expected = [
+        (AccountingType::LOFO, [100, 150, 250, 300]),
+        (AccountingType::HTFO, [250, 300, 100, 150]), // ST loss, LT loss, LT gain, ST gain
+        (AccountingType::MinTax, [300, 250, 150, 100]), // Tax per unit at 37% / 20%: -20, -18.5, 18.5, 20
+    ];
+
+    for (acct_type, costs) in expected {
+        config.accounting_type = acct_type;
+        let trades = funcs::import_trades::import_trades(&config).unwrap();
+        let (sale_events, _) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config).unwrap();
+
+        let consumed: Vec<Decimal> = sale_events.iter().map(|sale| sale.cost_basis).collect();
+        assert_eq!(consumed, costs.map(|cost| Decimal::new(cost, 0)), "{:?}", config.accounting_type);
+    }
+}
+
+
+#[test]
+fn shortfall_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/shortfall_transactions.csv");
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+
+    // Selling 3 with only 1 held fails by default
+    let error = funcs::process_trades::process_trades(trades.clone(), &config).unwrap_err();
+    match error {
+        CryptotaxError::Inventory(InventoryError::InsufficientInventory { asset, unmatched_amount, .. }) => {
+            assert_eq!((asset.as_str(), unmatched_amount), ("ABC", Decimal::new(2, 0)));
+        }
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    // Zero basis: the unmatched 2 are all gain
+    config.inventory.policy = ShortfallPolicy::ZeroBasis;
+    let processed = funcs::process_trades::process_trades(trades.clone(), &config).unwrap();
+    assert_eq!(processed.shortfalls.len(), 1);
+    let zero_basis = processed.sale_events.iter().find(|sale| sale.match_rule == MatchRule::ZeroBasis).unwrap();
+    assert_eq!((zero_basis.amount, zero_basis.cost_basis, zero_basis.gain_loss), (Decimal::new(2, 0), Decimal::ZERO, Decimal::new(400, 0)));
+
+    // Opening balance of 5 @ 50: 2 are taken from it and 3 remain open
+    config.inventory.policy = ShortfallPolicy::OpeningBalance;
+    config.inventory.opening_balances.insert("ABC".to_string(), OpeningBalance {
+        amount: Decimal::new(5, 0),
+        cost_basis: Decimal::new(250, 0),
+        acquired: funcs::trade::parse_datetime_string("2020-01-01 00:00:00").unwrap(),
+    });
+    let processed = funcs::process_trades::process_trades(trades.clone(), &config).unwrap();
+    let opening = processed.sale_events.iter().find(|sale| sale.match_rule == MatchRule::OpeningBalance).unwrap();
+    assert_eq!((opening.amount, opening.cost_basis), (Decimal::new(2, 0), Decimal::new(100, 0)));
+    assert_eq!(processed.open_lots["ABC"].iter().map(|lot| lot.remaining).sum::<Decimal>(), Decimal::new(3, 0));
+
+    // The opening balance is only a lot under its own policy
+    config.inventory.policy = ShortfallPolicy::ZeroBasis;
+    let processed = funcs::process_trades::process_trades(trades, &config).unwrap();
+    assert!(processed.open_lots["ABC"].is_empty());
+}
+
+
+#[test]
+fn error_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+
+    // A bad row reports the file, line and column instead of panicking
+    let filepath = std::env::temp_dir().join("cryptotax_zero_amount.csv");
+    std::fs::write(&filepath, "Date,Type,Asset,Amount,Quote,Price\n\
+        2019-01-01T00:00:00Z,BUY,ABC,1,USD,100\n\
+        2019-02-01T00:00:00Z,SELL,ABC,0,USD,100\n").unwrap();
+    config.csv_columns = [("Date", "timestamp"), ("Type", "txn_type"), ("Asset", "base_asset"), ("Amount", "base_asset_amount"), ("Quote", "quote_asset"), ("Price", "quote_asset_amount")]
+        .into_iter()
+        .map(|(header, name)| (header.to_string(), name.to_string()))
+        .collect();
+    config.filepath = filepath;
+
+    match funcs::import_trades::import_trades(&config).unwrap_err() {
+        CryptotaxError::Record { line, source, .. } => assert_eq!((line, source.column.as_str()), (3, "base_asset_amount")),
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    // A sell-only asset falls to the shortfall policy rather than panicking
+    std::fs::write(&config.filepath, "Date,Type,Asset,Amount,Quote,Price\n2019-02-01T00:00:00Z,SELL,ABC,1,USD,100\n").unwrap();
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    assert!(matches!(funcs::process_trades::process_trades(trades, &config).unwrap_err(), CryptotaxError::Inventory(_)));
+
+    // An unknown accounting type is a config error
+    let config_filepath = std::env::temp_dir().join("cryptotax_bad_config.ini");
+    std::fs::write(&config_filepath, "[accounting_type]\naccounting_type = NOPE\n").unwrap();
+    assert!(matches!(funcs::config::build_config(config_filepath).unwrap_err(), ConfigParseError::InvalidValue { .. }));
+}
+
+
+#[test]
+fn import_errors_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.csv_columns = [("Date", "timestamp"), ("Type", "txn_type"), ("Asset", "base_asset"), ("Amount", "base_asset_amount"), ("Quote", "quote_asset"), ("Price", "quote_asset_amount")]
+        .into_iter()
+        .map(|(header, name)| (header.to_string(), name.to_string()))
+        .collect();
+    config.filepath = std::env::temp_dir().join("cryptotax_import_errors.csv");
+    std::fs::write(&config.filepath, "Date,Type,Asset,Amount,Quote,Price\n\
+        2019-01-01T00:00:00Z,BUY,ABC,2,USD,200\n\
+        01/02/2019,BUY,ABC,1,USD,100\n\
+        2019-03-01T00:00:00Z,SELL,ABC,1,USD,x\n\
+        2019-04-01T00:00:00Z,SELL,ABC,1,USD,150\n\
+        2019-05-01T00:00:00Z,BUY,ABC,1,USD,100,extra\n\
+        2019-06-01T00:00:00Z,BUY,ABC\n").unwrap();
+
+    // Collect: every bad row is reported at once, including rows with the wrong number of fields
+    config.import_errors = ImportErrorPolicy::Collect;
+    match funcs::import_trades::import_trades(&config).unwrap_err() {
+        CryptotaxError::InvalidRows(errors) => {
+            let located: Vec<_> = errors.iter().map(|error| (error.line, error.column.as_str())).collect();
+            assert_eq!(located, vec![(3, "timestamp"), (4, "quote_asset_amount"), (6, "row"), (7, "row")]);
+        }
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    // Skip: the valid rows are still processed
+    config.import_errors = ImportErrorPolicy::Skip;
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+    assert_eq!(report.import_errors.len(), 4);
+    assert_eq!(report.sale_events.len(), 1);
+    assert_eq!(report.sale_events[0].gain_loss, Decimal::new(50, 0));
+}
+
+
+#[test]
+fn price_source_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/crypto_transactions.csv");
+    config.price_source.filepath = PathBuf::from("tests/prices.csv");
+
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+
+    // Selling 1 BTC for 20 ETH disposes of BTC at 15000 and acquires ETH at 20 * 750
+    let eth_leg = trades["ETH"].iter().find(|trade| trade.txn_id.as_deref() == Some("3")).unwrap();
+    assert_eq!((eth_leg.quote_asset.as_str(), eth_leg.quote_asset_amount), ("USD", Decimal::new(15000, 0)));
+
+    let (sale_events, _) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config).unwrap();
+    let mut gains: Vec<(String, Decimal)> = sale_events.iter().map(|sale| (sale.name.to_owned(), sale.gain_loss)).collect();
+    gains.sort();
+    assert_eq!(gains, vec![
+        ("BTC".to_string(), Decimal::new(5000, 0)),
+        ("ETH".to_string(), Decimal::new(3750, 0)),
+        ("ETH".to_string(), Decimal::new(9000, 0)),
+    ]);
+
+    // Without a price at or before the trade it can't be valued
+    let mut history = funcs::price_source::PriceHistory::from_file(&config.price_source.filepath).unwrap();
+    let early = funcs::trade::parse_datetime_string("2018-12-31").unwrap();
+    assert_eq!(history.price_at("BTC", early), None);
+    history.insert("BTC", early, Decimal::new(9000, 0));
+    assert_eq!(history.price_at("BTC", early), Some(Decimal::new(9000, 0)));
+
+    // With venues, the acquired leg lands on the trade's venue so a later sale there can use it
+    config.venues = Some(vec![]);
+    config.csv_columns.insert("Venue".to_string(), "venue".to_string());
+    config.filepath = std::env::temp_dir().join("cryptotax_venue_prices.csv");
+    let venue_csv: String = std::fs::read_to_string("tests/crypto_transactions.csv").unwrap().lines()
+        .enumerate()
+        .map(|(i, line)| format!("{},{}\n", line, if i == 0 { "Venue" } else { "Kraken" }))
+        .collect();
+    std::fs::write(&config.filepath, venue_csv).unwrap();
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+    let eth_sold: Vec<(&str, Decimal)> = report.sale_events.iter().filter(|sale| sale.name == "ETH").map(|sale| (sale.venue.as_str(), sale.amount)).collect();
+    assert_eq!(eth_sold, vec![("Kraken", Decimal::new(10, 0)), ("Kraken", Decimal::new(15, 0))]);
+}
+
+
+#[test]
+fn fx_rates_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/fx_transactions.csv");
+    config.fx_rates = PathBuf::from("tests/fx_rates.csv");
+
+    // Bought for 10000 EUR at 1.1, sold for 12000 EUR at 1.2
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let (sale_events, _) = funcs::process_trades::get_sale_events_and_cost_basis(trades, &config).unwrap();
+
+    let sale = &sale_events[0];
+    assert_eq!((sale.cost_basis, sale.proceeds, sale.gain_loss), (Decimal::new(11000, 0), Decimal::new(14400, 0), Decimal::new(3400, 0)));
+    assert_eq!((sale.buy_currency.as_str(), sale.buy_fx_rate), ("EUR", Decimal::new(11, 1)));
+    assert_eq!((sale.sale_currency.as_str(), sale.sale_fx_rate), ("EUR", Decimal::new(12, 1)));
+
+    // A fiat quote missing from the rate table is an error, not a 1:1 conversion
+    config.filepath = std::env::temp_dir().join("cryptotax_fx_missing_rate.csv");
+    std::fs::write(&config.filepath, "Date,Internal Txn Identifier,TxnType,Base Asset,Base Asset Amt,Quote Asset,Quote Asset Amt\n\
+        2019-01-01T10:00:00Z,1,BUY,BTC,1,GBP,100\n").unwrap();
+    match funcs::import_trades::import_trades(&config).unwrap_err() {
+        CryptotaxError::Price(PriceError::MissingPrice { asset, .. }) => assert_eq!(asset, "GBP"),
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+
+#[test]
+fn income_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/income_transactions.csv");
+
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+
+    // Income lots are matched like buys, at their value on receipt
+    let gains: Vec<Decimal> = report.sale_events.iter().map(|sale| sale.gain_loss).collect();
+    assert_eq!(gains, vec![Decimal::new(1900, 0), Decimal::new(250, 0)]);
+
+    let kinds: Vec<IncomeKind> = report.income_events.iter().map(|event| event.kind).collect();
+    assert_eq!(kinds, vec![IncomeKind::Staking, IncomeKind::Airdrop, IncomeKind::Staking]);
+
+    // Kinds as rows, years as columns
+    let staking_2021 = report.income_summary.column("2021").unwrap().cast(&DataType::String).unwrap();
+    assert_eq!(staking_2021.str().unwrap().get(0), Some("750"));
+    assert_eq!(report.income_summary.shape(), (2, 3));
+}
+
+
+#[test]
+fn transfer_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/transfer_transactions.csv");
+    config.csv_columns.insert("Venue".to_string(), "venue".to_string());
+
+    let report = cryptotax::TaxEngine::new(config.clone()).run().unwrap();
+
+    // 1 BTC moved from Coinbase to Ledger, arriving as 0.999
+    let matched = &report.transfers.matched;
+    assert_eq!(matched.len(), 1);
+    assert_eq!((matched[0].from_venue.as_deref(), matched[0].to_venue.as_deref()), (Some("Coinbase"), Some("Ledger")));
+    assert_eq!(matched[0].network_fee, Decimal::new(1, 3));
+
+    // The network fee is a disposal, the rest keeps its original basis
+    let gains: Vec<(Decimal, Decimal)> = report.sale_events.iter().map(|sale| (sale.amount, sale.gain_loss)).collect();
+    assert_eq!(gains, vec![(Decimal::new(1, 3), Decimal::new(10, 0)), (Decimal::new(999, 3), Decimal::new(19980, 0))]);
+
+    // Processing without the engine pairs the transfers too
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let processed = funcs::process_trades::process_trades(trades, &config).unwrap();
+    assert_eq!(processed.transfers.matched.len(), 1);
+    assert_eq!(processed.sale_events.iter().map(|sale| (sale.amount, sale.gain_loss)).collect::<Vec<(Decimal, Decimal)>>(), gains);
+
+    // A deposit with no withdrawal is left for review
+    let unmatched = &report.transfers.unmatched;
+    assert_eq!(unmatched.len(), 1);
+    assert_eq!((unmatched[0].txn_id.as_deref(), unmatched[0].direction), (Some("4"), TransferDirection::Deposit));
+
+    // Without a value on the withdrawal the fee is reported, not sold at zero
+    config.filepath = std::env::temp_dir().join("cryptotax_unvalued_transfer.csv");
+    std::fs::write(&config.filepath, std::fs::read_to_string("tests/transfer_transactions.csv").unwrap().replace("WITHDRAW,BTC,1,USD,20000", "WITHDRAW,BTC,1,USD,")).unwrap();
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+    assert_eq!(report.transfers.unvalued_fees.iter().map(|transfer| transfer.network_fee).collect::<Vec<Decimal>>(), vec![Decimal::new(1, 3)]);
+    assert_eq!(report.sale_events.iter().map(|sale| sale.amount).collect::<Vec<Decimal>>(), vec![Decimal::new(999, 3)]);
+}
+
+
+#[test]
+fn venue_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/venue_transactions.csv");
+    config.csv_columns.insert("Venue".to_string(), "venue".to_string());
+    config.venues = Some(vec![]);
+
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+
+    // The Kraken sale can only use the Kraken lot, and the Coinbase lot moved to Ledger keeps its basis and date
+    let sales: Vec<(&str, Decimal, Decimal)> = report.sale_events.iter().map(|sale| (sale.venue.as_str(), sale.cost_basis, sale.gain_loss)).collect();
+    assert_eq!(sales, vec![
+        ("Kraken", Decimal::new(20000, 0), Decimal::new(20000, 0)),
+        ("Ledger", Decimal::new(10000, 0), Decimal::new(30000, 0)),
+    ]);
+    assert_eq!(report.sale_events[1].holding_period, HoldingPeriod::ShortTerm);
+    assert_eq!(report.sale_events[1].buy_date, funcs::trade::parse_datetime_string("2020-01-01").unwrap());
+
+    // Rows on a venue that isn't listed are rejected
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.filepath = PathBuf::from("tests/venue_transactions.csv");
+    config.csv_columns.insert("Venue".to_string(), "venue".to_string());
+    config.venues = Some(vec!["Coinbase".to_string(), "Kraken".to_string()]);
+    match funcs::import_trades::import_trades(&config).unwrap_err() {
+        CryptotaxError::Record { line, source, .. } => assert_eq!((line, source.column.as_str()), (5, "venue")),
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+
+#[test]
+fn safe_harbor_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/safe_harbor_transactions.csv");
+    config.csv_columns.insert("Venue".to_string(), "venue".to_string());
+    config.venues = Some(vec![]);
+    let cutover = funcs::trade::parse_datetime_string("2021-01-01").unwrap();
+
+    // The oldest open lot fills the first wallet by name, and the allocation is the same every run
+    let engine = cryptotax::TaxEngine::new(config.clone());
+    let allocation = engine.safe_harbor_allocation(cutover, &PathBuf::from("tests/wallet_balances.csv")).unwrap();
+    let lots: Vec<(Option<&str>, Decimal, Decimal)> = allocation.iter().map(|lot| (lot.wallet.as_deref(), lot.amount, lot.cost_basis)).collect();
+    assert_eq!(lots, vec![
+        (Some("Kraken"), Decimal::new(5, 1), Decimal::new(5000, 0)),
+        (Some("Ledger"), Decimal::new(1, 0), Decimal::new(20000, 0)),
+    ]);
+    assert_eq!(allocation, engine.safe_harbor_allocation(cutover, &PathBuf::from("tests/wallet_balances.csv")).unwrap());
+
+    // Balances that don't add up to the open lots are rejected
+    let balances_filepath = std::env::temp_dir().join("cryptotax_wallet_balances.csv");
+    std::fs::write(&balances_filepath, "wallet,asset,amount\nLedger,BTC,2\n").unwrap();
+    match engine.safe_harbor_allocation(cutover, &balances_filepath).unwrap_err() {
+        CryptotaxError::SafeHarbor(SafeHarborError::BalanceMismatch { asset, .. }) => assert_eq!(asset, "BTC"),
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    // After the cut-over the Ledger sale uses the lot allocated to Ledger, not the universal FIFO lots
+    let allocation_filepath = std::env::temp_dir().join("cryptotax_safe_harbor_allocation.csv");
+    let mut writer = csv::Writer::from_path(&allocation_filepath).unwrap();
+    for lot in &allocation {
+        writer.serialize(lot).unwrap();
+    }
+    writer.flush().unwrap();
+    assert_eq!(funcs::starting_lots::import_starting_lots(&allocation_filepath).unwrap(), allocation);
+
+    config.safe_harbor = Some(SafeHarborConfig { filepath: allocation_filepath, cutover });
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+    let sales: Vec<(&str, Decimal, Decimal)> = report.sale_events.iter().map(|sale| (sale.venue.as_str(), sale.cost_basis, sale.gain_loss)).collect();
+    assert_eq!(sales, vec![
+        ("Kraken", Decimal::new(5000, 0), Decimal::new(10000, 0)),
+        ("Ledger", Decimal::new(20000, 0), Decimal::new(30000, 0)),
+    ]);
+    assert_eq!(report.open_lots["BTC"].iter().map(|lot| lot.remaining).sum::<Decimal>(), Decimal::new(5, 1));
+}
+
+
+#[test]
+fn open_lots_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/safe_harbor_transactions.csv");
+
+    // Half of the second buy is left, long-term as of the last trade
+    let report = cryptotax::TaxEngine::new(config.clone()).run().unwrap();
+    assert_eq!(report.open_lot_report.len(), 1);
+    let open_lot = &report.open_lot_report[0];
+    assert_eq!(open_lot.name, "BTC");
+    assert_eq!(open_lot.acquired, funcs::trade::parse_datetime_string("2020-02-01").unwrap());
+    assert_eq!(open_lot.txn_id.as_deref(), Some("2"));
+    assert_eq!((open_lot.remaining, open_lot.unit_cost, open_lot.cost_basis), (Decimal::new(5, 1), Decimal::new(20000, 0), Decimal::new(10000, 0)));
+    assert_eq!(open_lot.holding_period, HoldingPeriod::LongTerm);
+
+    // Still short-term at the end of 2020
+    config.open_lots_as_of = Some(funcs::trade::parse_datetime_string("2020-12-31").unwrap());
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+    assert_eq!(report.open_lot_report[0].holding_period, HoldingPeriod::ShortTerm);
+
+    let json = serde_json::to_value(&report.open_lot_report).unwrap();
+    assert_eq!(json[0]["acquired"], "2020-02-01 00:00:00");
+    assert_eq!(json[0]["holding_period"], "Short-Term");
+    assert_eq!(json[0]["cost_basis"].as_str().unwrap().parse::<Decimal>().unwrap(), Decimal::new(10000, 0));
+}
+
+
+#[test]
+fn opening_lots_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/safe_harbor_transactions.csv");
+    config.opening_lots = PathBuf::from("tests/opening_lots.csv");
+
+    // The carried-in 2017 lot is sold first, then the oldest buy
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+    let sales: Vec<(Decimal, Decimal, HoldingPeriod)> = report.sale_events.iter().map(|sale| (sale.amount, sale.cost_basis, sale.holding_period)).collect();
+    assert_eq!(sales, vec![
+        (Decimal::new(5, 1), Decimal::new(600, 0), HoldingPeriod::LongTerm),
+        (Decimal::new(5, 1), Decimal::new(600, 0), HoldingPeriod::LongTerm),
+        (Decimal::new(5, 1), Decimal::new(5000, 0), HoldingPeriod::LongTerm),
+    ]);
+    assert_eq!(report.sale_events[0].buy_date, funcs::trade::parse_datetime_string("2017-03-01").unwrap());
+}
+
+
+#[test]
+fn txn_rule_test() {
+
+    let rules = "
+[txn_rule.unstake_buyback]
+txn_type = other
+pattern = UNSTAKE_BUYBACK
+precedence = -1
+
+[txn_rule.otc_sell]
+txn_type = sell
+pattern = ^Trade$
+match = regex
+notes = (?i)sold
+
+[txn_rule.otc_buy]
+txn_type = buy
+pattern = Trade
+notes = (?i)bought
+
+[txn_rule.lowercase_sell]
+txn_type = sell
+pattern = SELL
+match = case_insensitive
+
+[txn_rule.eth_convert]
+txn_type = buy
+pattern = CONVERT
+match = prefix
+asset = eth
+";
+    let config_filepath = std::env::temp_dir().join("cryptotax_txn_rules.ini");
+    std::fs::write(&config_filepath, std::fs::read_to_string("tests/test_config.ini").unwrap() + rules).unwrap();
+
+    let mut config = funcs::config::build_config(config_filepath).unwrap();
+    config.filepath = PathBuf::from("tests/rule_transactions.csv");
+    config.csv_columns.insert("Notes".to_string(), "notes".to_string());
+    assert_eq!(config.txn_rules[0].name, "unstake_buyback");
+
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let mut txn_types: Vec<(String, TxnType)> = trades.values().flatten().map(|trade| (trade.txn_id.clone().unwrap(), trade.txn_type.clone())).collect();
+    txn_types.sort_by_key(|(txn_id, _)| txn_id.parse::<u32>().unwrap());
+    assert_eq!(txn_types.into_iter().map(|(_, txn_type)| txn_type).collect::<Vec<TxnType>>(), vec![
+        TxnType::Buy,
+        TxnType::Other, // Set by a rule, so not reported as unclassified
+        TxnType::Buy,
+        TxnType::Sale,
+        TxnType::Other,
+        TxnType::Sale,
+        TxnType::Other,
+        TxnType::Buy,
+        TxnType::Other, // The prefix rule is limited to ETH
+    ]);
+
+    // Types a rule set to Other aren't reported
+    let report = cryptotax::TaxEngine::new(config.clone()).run().unwrap();
+    let unclassified: Vec<(&str, usize)> = report.unclassified_txn_types.iter().map(|txn_type| (txn_type.txn_type.as_str(), txn_type.trades)).collect();
+    assert_eq!(unclassified, vec![("CONVERT_IN", 1), ("Rebate", 2)]);
+
+    // Amount sign conditions
+    let mut record = funcs::import_trades::CsvRecord {
+        user_txn_id: None,
+        timestamp: "2020-01-01".to_string(),
+        txn_type: "TRADE".to_string(),
+        base_asset: "BTC".to_string(),
+        base_asset_amount: "-1".to_string(),
+        quote_asset: "USD".to_string(),
+        quote_asset_amount: "100".to_string(),
+        fee_amount: None,
+        fee_asset: None,
+        fee_value: None,
+        venue: None,
+        notes: None,
+        tx_hash: None,
+    };
+    config.txn_rules = vec![TxnRule {
+        name: "signed_trade".to_string(),
+        txn_type: TxnType::Sale,
+        pattern: TxnPattern::new("exact", "TRADE").unwrap(),
+        assets: vec![],
+        notes: None,
+        amount_sign: Some(AmountSign::Negative),
+        precedence: 0,
+    }];
+    assert_eq!(TxnType::classify(&record, &config), Some(TxnType::Sale));
+    record.base_asset_amount = "1".to_string();
+    assert_eq!(TxnType::classify(&record, &config), None);
+
+    // A negative amount is sold in full, not left under the dust threshold
+    config.filepath = PathBuf::from("tests/signed_transactions.csv");
+    let report = cryptotax::TaxEngine::new(config.clone()).run().unwrap();
+    let sales: Vec<(Decimal, Decimal)> = report.sale_events.iter().map(|sale| (sale.amount, sale.proceeds)).collect();
+    assert_eq!(sales, vec![(Decimal::new(1, 0), Decimal::new(12000, 0))]);
+    assert!(report.open_lots.values().flatten().all(|lot| lot.remaining.is_zero()));
+
+    // List entries match the whole type, ignoring case, not any type containing them
+    let config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    for (raw_type, expected) in [("UNSTAKE_BUYBACK", None), ("UNSTAKING", None), ("staking", Some(TxnType::Income(IncomeKind::Staking))), ("BUY (NFT Swap)", Some(TxnType::Buy))] {
+        record.txn_type = raw_type.to_string();
+        assert_eq!(TxnType::classify(&record, &config), expected, "{}", raw_type);
+    }
+
+    // Invalid regex is a config error
+    let config_filepath = std::env::temp_dir().join("cryptotax_bad_txn_rule.ini");
+    std::fs::write(&config_filepath, std::fs::read_to_string("tests/test_config.ini").unwrap() + "\n[txn_rule.bad]\ntxn_type = buy\npattern = (\nmatch = regex\n").unwrap();
+    assert!(matches!(funcs::config::build_config(config_filepath), Err(ConfigParseError::InvalidValue { key, .. }) if key == "pattern"));
+}
+
+
+#[test]
+fn opt_csv_columns_test() {
+
+    let config_filepath = std::env::temp_dir().join("cryptotax_opt_csv_columns.ini");
+    let ini = std::fs::read_to_string("tests/test_config.ini").unwrap()
+        .replace("; notes = Notes", "notes = Notes")
+        .replace("; tx_hash = Tx Hash", "tx_hash = Tx Hash");
+    std::fs::write(&config_filepath, ini).unwrap();
+
+    let mut config = funcs::config::build_config(config_filepath).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/traced_transactions.csv");
+    assert_eq!(config.csv_columns.get("Internal Txn Identifier").map(String::as_str), Some("user_txn_id"));
+
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let first_buy = trades["BTC"].iter().find(|trade| trade.txn_id.as_deref() == Some("B1")).unwrap();
+    assert_eq!((first_buy.notes.as_deref(), first_buy.tx_hash.as_deref()), (Some("First buy"), Some("0xabc")));
+    assert!(trades["BTC"].iter().find(|trade| trade.txn_id.as_deref() == Some("B2")).unwrap().notes.is_none());
+
+    // Each sale line traces back to its buy and sale rows
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+    let traced: Vec<(&str, &str, Decimal)> = report.sale_events.iter().map(|sale| (sale.buy_txn_id.as_str(), sale.sale_txn_id.as_str(), sale.amount)).collect();
+    assert_eq!(traced, vec![("B1", "S1", Decimal::new(1, 0)), ("B2", "S1", Decimal::new(5, 1))]);
+
+    // Only the optional fields can be mapped
+    let config_filepath = std::env::temp_dir().join("cryptotax_bad_opt_csv_columns.ini");
+    std::fs::write(&config_filepath, std::fs::read_to_string("tests/test_config.ini").unwrap().replace("user_txn_id = Internal", "txn_type = Internal")).unwrap();
+    assert!(matches!(funcs::config::build_config(config_filepath), Err(ConfigParseError::InvalidValue { section, .. }) if section == "opt_csv_columns"));
+}
+
+
+#[test]
+fn swap_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.filepath = PathBuf::from("tests/swap_transactions.csv");
+
+    // Both legs take the disposal's value and the earlier leg's time
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let nft_x = &trades["NFT_X"][0];
+    assert_eq!((nft_x.quote_asset_amount, nft_x.trade_time), (Decimal::new(1000, 0), funcs::trade::parse_datetime_string("2020-01-01 10:00:00").unwrap()));
+    assert_eq!(trades["NFT_Z"][0].quote_asset_amount, Decimal::new(1480, 0));
+
+    let report = cryptotax::TaxEngine::new(config.clone()).run().unwrap();
+    let sol_sale = report.sale_events.iter().find(|sale| sale.name == "SOL").unwrap();
+    assert_eq!((sol_sale.proceeds, sol_sale.cost_basis), (Decimal::new(1000, 0), Decimal::new(750, 0)));
+
+    // A priced side is more reliable than the stated values
+    config.price_source.filepath = PathBuf::from("tests/prices.csv");
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    assert_eq!(trades["NFT_Z"][0].quote_asset_amount, Decimal::new(1500, 0));
+    assert_eq!(trades["ETH"].iter().find(|trade| trade.txn_type == TxnType::Sale).unwrap().quote_asset_amount, Decimal::new(1500, 0));
+
+    config.price_source.filepath = PathBuf::new();
+    config.swaps.fair_value = SwapValuation::Acquisition;
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    assert_eq!(trades["SOL"].iter().find(|trade| trade.txn_type == TxnType::Sale).unwrap().quote_asset_amount, Decimal::new(1010, 0));
+
+    // Legs further apart than the tolerance are rejected
+    config.swaps.tolerance = Decimal::new(1, 3);
+    match funcs::import_trades::import_trades(&config).unwrap_err() {
+        CryptotaxError::Swap(SwapError::Unbalanced { txn_id, .. }) => assert_eq!(txn_id, "S1"),
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+
+#[test]
+fn ordering_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::LIFO;
+    config.filepath = PathBuf::from("tests/tie_transactions.csv");
+
+    // The sale shares a timestamp with buys B and A, listed after it
+    for (tie_breaker, expected_buy) in [(TieBreaker::BuysFirst, "A"), (TieBreaker::SourceOrder, "C"), (TieBreaker::TxnId, "B")] {
+        config.tie_breaker = tie_breaker;
+        let report = cryptotax::TaxEngine::new(config.clone()).run().unwrap();
+        assert_eq!(report.sale_events.len(), 1);
+        assert_eq!(report.sale_events[0].buy_txn_id, expected_buy, "{:?}", tie_breaker);
+    }
+
+    // Numeric txn ids compare as numbers, so buy 9 comes before sale 10 but buy 11 doesn't
+    config.tie_breaker = TieBreaker::TxnId;
+    config.filepath = std::env::temp_dir().join("cryptotax_numeric_txn_ids.csv");
+    std::fs::write(&config.filepath, "Date,Internal Txn Identifier,TxnType,Base Asset,Base Asset Amt,Quote Asset,Quote Asset Amt\n\
+        2020-01-01T00:00:00Z,1,BUY,BTC,1,USD,100\n\
+        2020-01-02T00:00:00Z,10,SELL,BTC,1,USD,500\n\
+        2020-01-02T00:00:00Z,11,BUY,BTC,1,USD,300\n\
+        2020-01-02T00:00:00Z,9,BUY,BTC,1,USD,200\n").unwrap();
+    let report = cryptotax::TaxEngine::new(config.clone()).run().unwrap();
+    assert_eq!(report.sale_events[0].buy_txn_id, "9");
+
+    // Per venue, the tie-breaker orders a withdrawal before the sale it funds on the same timestamp
+    let mut per_venue = config.clone();
+    per_venue.venues = Some(vec![]);
+    per_venue.csv_columns.insert("Venue".to_string(), "venue".to_string());
+    per_venue.filepath = std::env::temp_dir().join("cryptotax_tied_transfer.csv");
+    std::fs::write(&per_venue.filepath, "Date,Internal Txn Identifier,TxnType,Base Asset,Base Asset Amt,Quote Asset,Quote Asset Amt,Venue\n\
+        2020-01-01T00:00:00Z,1,BUY,BTC,1,USD,100,Coinbase\n\
+        2020-01-02T00:00:00Z,2,WITHDRAW,BTC,1,USD,200,Coinbase\n\
+        2020-01-02T00:00:00Z,3,DEPOSIT,BTC,1,USD,,Ledger\n\
+        2020-01-02T00:00:00Z,4,SELL,BTC,1,USD,200,Ledger\n").unwrap();
+    let report = cryptotax::TaxEngine::new(per_venue).run().unwrap();
+    assert_eq!((report.sale_events[0].buy_txn_id.as_str(), report.shortfalls.len()), ("1", 0));
+
+    // Two runs over the same input give byte-identical reports
+    let sale_events_csv = || {
+        let report = cryptotax::TaxEngine::from_config_file(PathBuf::from("tests/test_config.ini")).unwrap().run().unwrap();
+        let mut writer = csv::Writer::from_writer(vec![]);
+        for sale_event in &report.sale_events {
+            writer.serialize(sale_event).unwrap();
+        }
+        (writer.into_inner().unwrap(), format!("{:?}", report.cost_bases))
+    };
+    assert_eq!(sale_events_csv(), sale_events_csv());
+}
+
+
+#[test]
+fn coinbase_format_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.file_format = FileFormat::Coinbase;
+    config.filepath = PathBuf::from("tests/coinbase_transactions.csv");
+
+    // The preamble above the header is skipped, rows keep their file line
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let buy = &trades["BTC"][0];
+    assert_eq!((&buy.txn_type, buy.base_asset_amount, buy.quote_asset_amount, buy.fee), (&TxnType::Buy, Decimal::new(1, 1), Decimal::new(2000, 0), Decimal::new(10, 0)));
+    assert_eq!((buy.venue.as_deref(), buy.source_row), (Some("coinbase"), 7));
+    assert_eq!(trades["ETH"][0].txn_type, TxnType::Income(IncomeKind::Staking));
+    assert_eq!(trades["USD"][0].txn_type, TxnType::Other);
+
+    // A Convert row becomes both legs of a swap
+    let convert: Vec<(&str, &TxnType, Decimal, Decimal)> = trades.values().flatten()
+        .filter(|trade| trade.txn_id.as_deref() == Some("cb3"))
+        .map(|trade| (trade.base_asset.as_str(), &trade.txn_type, trade.base_asset_amount, trade.quote_asset_amount))
+        .collect();
+    assert_eq!(convert, vec![("BTC", &TxnType::Sale, Decimal::new(5, 2), Decimal::new(1100, 0)), ("ETH", &TxnType::Buy, Decimal::new(7, 1), Decimal::new(1100, 0))]);
+
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+    assert_eq!(report.sale_events.iter().filter(|sale| sale.name == "ETH").map(|sale| sale.amount).sum::<Decimal>(), Decimal::new(5, 1));
+    assert_eq!(report.unclassified_txn_types.iter().map(|unclassified| unclassified.txn_type.as_str()).collect::<Vec<&str>>(), vec!["Retail Staking Transfer"]);
+}
+
+
+#[test]
+fn kraken_format_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.file_format = FileFormat::Kraken;
+    config.filepath = PathBuf::from("tests/kraken_ledgers.csv");
+    config.price_source.filepath = PathBuf::from("tests/prices.csv");
+
+    // Ledger rows sharing a refid are one trade, in the usual tickers
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let btc: Vec<(Option<&str>, &TxnType, Decimal, Decimal, Decimal)> = trades["BTC"].iter()
+        .map(|trade| (trade.txn_id.as_deref(), &trade.txn_type, trade.base_asset_amount, trade.quote_asset_amount, trade.fee))
+        .collect();
+    assert_eq!(btc, vec![
+        (Some("T1"), &TxnType::Buy, Decimal::new(1, 1), Decimal::new(2000, 0), Decimal::new(32, 1)),
+        (Some("T2"), &TxnType::Sale, Decimal::new(4, 2), Decimal::new(600, 0), Decimal::ZERO), // Crypto-to-crypto, valued from the price history
+        (Some("T3"), &TxnType::Sale, Decimal::new(5, 2), Decimal::new(1400, 0), Decimal::new(224, 2)),
+    ]);
+    assert_eq!((&trades["DOT"][0].txn_type, trades["DOT"][0].quote_asset_amount), (&TxnType::Income(IncomeKind::Staking), Decimal::new(125, 1)));
+
+    // Withdrawals include their network fee
+    let withdrawal = trades["ETH"].iter().find(|trade| trade.txn_type == TxnType::Withdrawal).unwrap();
+    assert_eq!(withdrawal.base_asset_amount, Decimal::new(205, 3));
+    let unclassified = funcs::txn_type::get_unclassified_txn_types(&trades);
+    assert_eq!(unclassified.iter().map(|unclassified| unclassified.txn_type.as_str()).collect::<Vec<&str>>(), vec!["margin"]);
+
+    // The trades export, with old and new pair names
+    config.filepath = PathBuf::from("tests/kraken_trades.csv");
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    assert_eq!((trades["BTC"][0].base_asset_amount, &trades["BTC"][0].quote_asset), (Decimal::new(1, 1), &"USD".to_string()));
+    assert_eq!((&trades["ETH"][0].txn_type, trades["ETH"][0].fee), (&TxnType::Sale, Decimal::new(86, 2)));
+}
+
+
+#[test]
+fn binance_format_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.file_format = FileFormat::Binance;
+    config.filepath = PathBuf::from("tests/binance_trades.csv");
+
+    // The BNB fee can't be valued without a price history
+    match funcs::import_trades::import_trades(&config).unwrap_err() {
+        CryptotaxError::Record { line, source, .. } => assert_eq!((line, source.column.as_str()), (3, "fee_value")),
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    config.price_source.filepath = PathBuf::from("tests/prices.csv");
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let eth_buy = trades["ETH"].iter().find(|trade| trade.txn_type == TxnType::Buy).unwrap();
+    assert_eq!((eth_buy.base_asset_amount, eth_buy.fee_asset.as_str(), eth_buy.fee_asset_amount), (Decimal::new(2, 0), "BNB", Decimal::new(1, 2)));
+    assert_eq!(trades["BNB"][0].quote_asset_amount, Decimal::new(6, 2));
+
+    // A ticker starting with a digit isn't read as part of the amount
+    let inch_buy = trades["1INCH"].iter().find(|trade| trade.txn_type == TxnType::Buy).unwrap();
+    assert_eq!((inch_buy.base_asset_amount, inch_buy.fee_asset.as_str(), inch_buy.fee_asset_amount), (Decimal::new(105, 1), "1INCH", Decimal::new(105, 4)));
+
+    // USDT is priced, so every BTC leg is in USD. The BTC fee on the buy is disposed of, and older exports give the same trades
+    let amounts = |trades: &funcs::import_trades::SortedTrades| -> Vec<(Decimal, Decimal, Decimal)> {
+        trades["BTC"].iter().map(|trade| (trade.base_asset_amount, trade.quote_asset_amount, trade.fee)).collect()
+    };
+    assert!(trades["BTC"].iter().all(|trade| trade.quote_asset == "USD"));
+    let new_format = amounts(&trades);
+    assert_eq!(new_format, vec![
+        (Decimal::new(5, 4), Decimal::new(5, 0), Decimal::ZERO),
+        (Decimal::new(5, 1), Decimal::new(5000, 0), Decimal::new(5, 0)),
+        (Decimal::new(2, 2), Decimal::new(200, 0), Decimal::ZERO),
+        (Decimal::new(2, 1), Decimal::new(3000, 0), Decimal::new(3, 0)),
+    ]);
+    config.filepath = PathBuf::from("tests/binance_trades_old.csv");
+    assert_eq!(amounts(&funcs::import_trades::import_trades(&config).unwrap()), new_format);
+
+    // Without a USDT price the trades can't be valued, rather than being booked as if USDT were USD
+    let prices = std::fs::read_to_string("tests/prices.csv").unwrap();
+    let filepath = std::env::temp_dir().join("cryptotax_prices_without_usdt.csv");
+    std::fs::write(&filepath, prices.lines().filter(|line| !line.starts_with("USDT,")).collect::<Vec<&str>>().join("\n")).unwrap();
+    config.price_source.filepath = filepath;
+    match funcs::import_trades::import_trades(&config).unwrap_err() {
+        CryptotaxError::Price(PriceError::MissingPrice { asset, .. }) => assert_eq!(asset, "USDT"),
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+
+#[test]
+fn gemini_format_test() {
+
+    let mut config = funcs::config::build_config(PathBuf::from("tests/test_config.ini")).unwrap();
+    config.accounting_type = AccountingType::FIFO;
+    config.file_format = FileFormat::Gemini;
+    config.filepath = PathBuf::from("tests/gemini_transactions.csv");
+
+    // Interest has no value in the export, so it needs a price
+    match funcs::import_trades::import_trades(&config).unwrap_err() {
+        CryptotaxError::Record { line, source, .. } => assert_eq!((line, source.column.as_str()), (4, "price")),
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    // The totals row at the end is not a trade
+    config.price_source.filepath = PathBuf::from("tests/prices.csv");
+    let trades = funcs::import_trades::import_trades(&config).unwrap();
+    let btc: Vec<(&TxnType, Decimal, Decimal, Decimal)> = trades["BTC"].iter()
+        .map(|trade| (&trade.txn_type, trade.base_asset_amount, trade.quote_asset_amount, trade.fee))
+        .collect();
+    assert_eq!(btc, vec![
+        (&TxnType::Buy, Decimal::new(1, 1), Decimal::new(2000, 0), Decimal::new(7, 0)),
+        (&TxnType::Income(IncomeKind::Interest), Decimal::new(1, 3), Decimal::new(15, 0), Decimal::ZERO),
+        (&TxnType::Sale, Decimal::new(5, 2), Decimal::new(1100, 0), Decimal::new(385, 2)),
+        (&TxnType::Withdrawal, Decimal::new(2, 2), Decimal::new(300, 0), Decimal::ZERO),
+    ]);
+    assert_eq!(trades["BTC"][3].tx_hash.as_deref(), Some("abc123"));
+    assert_eq!((&trades["USD"][0].txn_type, trades.values().flatten().count()), (&TxnType::Other, 5));
+
+    let report = cryptotax::TaxEngine::new(config).run().unwrap();
+    assert_eq!(report.sale_events[0].proceeds, Decimal::new(1100, 0) - Decimal::new(385, 2));
+}